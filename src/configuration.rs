@@ -6,8 +6,21 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use thiserror::Error;
 
+mod authority;
+pub(crate) use authority::*;
+mod index;
+pub(crate) use index::*;
 mod location;
 pub(crate) use location::*;
+mod pattern;
+pub(crate) use pattern::*;
+mod proxy_config;
+pub(crate) use proxy_config::*;
+mod responses;
+pub(crate) use responses::*;
+mod selector;
+pub(crate) use selector::*;
+mod validation;
 
 #[derive(Debug, Error)]
 pub(crate) enum MissingError {
@@ -24,6 +37,10 @@ pub(crate) struct System {
     name: Option<String>,
     upstream: Upstream,
     token: String,
+    // environment to fetch service configurations for
+    environment: Option<String>,
+    // seconds between fetches of service configurations, with 0 fetching them only once
+    refresh_interval: Option<u64>,
 }
 
 impl System {
@@ -38,6 +55,71 @@ impl System {
     pub fn token(&self) -> &str {
         self.token.as_str()
     }
+
+    pub fn environment(&self) -> &str {
+        self.environment
+            .as_deref()
+            .unwrap_or(DEFAULT_SYSTEM_ENVIRONMENT)
+    }
+
+    pub fn refresh_interval(&self) -> u64 {
+        self.refresh_interval
+            .unwrap_or(DEFAULT_SYSTEM_REFRESH_INTERVAL_SECS)
+    }
+}
+
+const DEFAULT_SYSTEM_ENVIRONMENT: &str = "production";
+const DEFAULT_SYSTEM_REFRESH_INTERVAL_SECS: u64 = 60;
+const DEFAULT_FAILURE_STATUS: u32 = 503;
+const DEFAULT_DEGRADED_TTL_SECS: u64 = 300;
+const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 1000;
+const DEFAULT_REPORT_INTERVAL_SECS: u64 = 10;
+const DEFAULT_REPORT_BATCH_SIZE: usize = 100;
+
+/// What to do with requests when the backend cannot be reached or fails.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FailureMode {
+    /// Reject the request.
+    Closed,
+    /// Let the request through and report its usage later.
+    Open,
+    /// Let the request through only for applications recently authorized by the backend.
+    Degraded,
+}
+
+impl Default for FailureMode {
+    fn default() -> Self {
+        Self::Closed
+    }
+}
+
+/// Local authorization cache settings. Authorizations are only cached when present.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Cache {
+    // seconds an authorization is used before asking the backend again
+    ttl: Option<u64>,
+    max_entries: Option<usize>,
+}
+
+impl Cache {
+    pub fn ttl(&self) -> u64 {
+        self.ttl.unwrap_or(DEFAULT_CACHE_TTL_SECS)
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.unwrap_or(DEFAULT_CACHE_MAX_ENTRIES)
+    }
+}
+
+/// Settings for reporting usage not reported with authorizations.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Reporting {
+    // maximum seconds between reports of pending usage
+    flush_interval: Option<u64>,
+    // maximum transactions per report call, with usage reported early once reached
+    max_batch_size: Option<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -45,6 +127,13 @@ pub(crate) struct Backend {
     name: Option<String>,
     upstream: Upstream,
     extensions: Option<Vec<String>>,
+    // status code sent downstream when the backend cannot be reached or fails
+    failure_status: Option<u32>,
+    failure_mode: Option<FailureMode>,
+    // how long, in seconds, an authorization is remembered for the degraded failure mode
+    degraded_ttl: Option<u64>,
+    cache: Option<Cache>,
+    reporting: Option<Reporting>,
 }
 
 impl Backend {
@@ -59,9 +148,58 @@ impl Backend {
     pub fn extensions(&self) -> Option<&Vec<String>> {
         self.extensions.as_ref()
     }
+
+    pub fn failure_status(&self) -> u32 {
+        self.failure_status.unwrap_or(DEFAULT_FAILURE_STATUS)
+    }
+
+    pub fn failure_mode(&self) -> Option<FailureMode> {
+        self.failure_mode
+    }
+
+    pub fn degraded_ttl(&self) -> u64 {
+        self.degraded_ttl.unwrap_or(DEFAULT_DEGRADED_TTL_SECS)
+    }
+
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub fn report_flush_interval(&self) -> u64 {
+        self.reporting
+            .as_ref()
+            .and_then(|reporting| reporting.flush_interval)
+            .unwrap_or(DEFAULT_REPORT_INTERVAL_SECS)
+    }
+
+    pub fn report_max_batch_size(&self) -> usize {
+        self.reporting
+            .as_ref()
+            .and_then(|reporting| reporting.max_batch_size)
+            .filter(|&size| size > 0)
+            .unwrap_or(DEFAULT_REPORT_BATCH_SIZE)
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+/// What to do with requests matching no mapping rule of their service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Unmatched {
+    /// Reject the request as not found.
+    Deny,
+    /// Let the request through without authorizing nor reporting it.
+    Allow,
+    /// Authorize and report the request with the given usage.
+    Report(Usage),
+}
+
+impl Default for Unmatched {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub(crate) enum ApplicationKind {
     UserKey,
@@ -102,10 +240,25 @@ impl<K> Parameter<K> {
 pub(crate) struct Service {
     id: String,
     token: String,
+    // authorities, credentials and mapping rules are fetched from System when left empty
+    #[serde(default)]
     authorities: Vec<String>,
+    // match authorities regardless of their port
+    ignore_port: Option<bool>,
+    // further conditions to tell apart services sharing authorities
+    selector: Option<Selector>,
+    #[serde(default)]
     credentials: Vec<Parameter<String>>,
+    #[serde(default)]
     mapping_rules: Vec<MappingRule>,
     valid_apps: Option<Vec<String>>,
+    responses: Option<Responses>,
+    // overrides the backend failure mode for this service
+    failure_mode: Option<FailureMode>,
+    // overrides the backend cache ttl for this service
+    cache_ttl: Option<u64>,
+    // policy for requests matching no mapping rule, denying them by default
+    unmatched: Option<Unmatched>,
 }
 
 impl Service {
@@ -121,6 +274,14 @@ impl Service {
         self.authorities.as_ref()
     }
 
+    pub fn ignore_port(&self) -> bool {
+        self.ignore_port.unwrap_or(false)
+    }
+
+    pub fn selector(&self) -> Option<&Selector> {
+        self.selector.as_ref()
+    }
+
     pub fn credentials(&self) -> Result<&Vec<Parameter<String>>, MissingError> {
         if self.credentials.is_empty() {
             Err(MissingError::Credentials(self.id.to_owned()))
@@ -137,8 +298,69 @@ impl Service {
         self.valid_apps.as_ref()
     }
 
-    pub fn match_authority(&self, authority: &str) -> bool {
-        self.authorities.iter().any(|auth| auth == authority)
+    pub fn responses(&self) -> Option<&Responses> {
+        self.responses.as_ref()
+    }
+
+    pub fn failure_mode(&self) -> Option<FailureMode> {
+        self.failure_mode
+    }
+
+    pub fn cache_ttl(&self) -> Option<u64> {
+        self.cache_ttl
+    }
+
+    pub fn unmatched(&self) -> Option<&Unmatched> {
+        self.unmatched.as_ref()
+    }
+
+    /// Fills in the authorities, credentials and mapping rules not configured in the plugin
+    /// with those of the configuration published by System.
+    pub fn merge_proxy_config(&mut self, proxy_config: &ProxyConfig) {
+        if self.authorities.is_empty() {
+            self.authorities = proxy_config.authorities();
+        }
+        if self.credentials.is_empty() {
+            self.credentials = proxy_config.credentials();
+        }
+        if self.mapping_rules.is_empty() {
+            self.mapping_rules = proxy_config.mapping_rules();
+        }
+    }
+
+    /// Returns the best match of the service authorities for a request authority, if any.
+    pub fn match_authority(&self, authority: &str) -> Option<AuthorityMatch> {
+        self.authorities
+            .iter()
+            .filter_map(|auth| match_authority(auth, authority, self.ignore_port()))
+            .max()
+    }
+
+    /// Checks whether a request, with headers looked up with `header`, belongs to the
+    /// service, returning how specifically it matched.
+    pub fn match_request<'h>(
+        &self,
+        authority: &str,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<&'h str>,
+    ) -> Option<ServiceMatch> {
+        let authority = self.match_authority(authority)?;
+        self.match_selector(authority, method, path, header)
+    }
+
+    /// Checks the selector of the service against a request whose authority matched.
+    pub fn match_selector<'h>(
+        &self,
+        authority: AuthorityMatch,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<&'h str>,
+    ) -> Option<ServiceMatch> {
+        match self.selector() {
+            Some(selector) => selector.matches(authority, method, path, header),
+            None => Selector::default().matches(authority, method, path, header),
+        }
     }
 }
 
@@ -147,6 +369,10 @@ pub(crate) struct MappingRule {
     method: String,
     pattern: String,
     usages: Vec<Usage>,
+    // stops evaluating further rules once this one matches
+    last: Option<bool>,
+    // precedence of the rule, with lower positions evaluated first
+    position: Option<u32>,
 }
 
 impl MappingRule {
@@ -162,8 +388,12 @@ impl MappingRule {
         self.usages.as_ref()
     }
 
-    pub fn match_pattern(&self, pattern: &str) -> bool {
-        pattern.starts_with(&self.pattern)
+    pub fn last(&self) -> bool {
+        self.last.unwrap_or(false)
+    }
+
+    pub fn position(&self) -> Option<u32> {
+        self.position
     }
 }
 
@@ -189,6 +419,8 @@ pub(crate) struct Configuration {
     system: Option<System>,
     backend: Option<Backend>,
     services: Option<Vec<Service>>,
+    // defaults for services not specifying their own, and responses for unknown services
+    responses: Option<Responses>,
 }
 
 impl TryFrom<&[u8]> for Configuration {
@@ -212,6 +444,21 @@ impl Configuration {
         self.services.as_ref()
     }
 
+    pub fn responses(&self) -> Option<&Responses> {
+        self.responses.as_ref()
+    }
+
+    pub fn get_service(&self, id: &str) -> Option<&Service> {
+        self.services()
+            .and_then(|services| services.iter().find(|svc| svc.id() == id))
+    }
+
+    pub fn get_service_mut(&mut self, id: &str) -> Option<&mut Service> {
+        self.services
+            .as_mut()
+            .and_then(|services| services.iter_mut().find(|svc| svc.id() == id))
+    }
+
     pub fn get_backend(&self) -> Result<&Backend, MissingError> {
         self.backend().ok_or(MissingError::Backend)
     }
@@ -221,6 +468,42 @@ impl Configuration {
     }
 }
 
+/// What to do with requests when no plugin configuration is loaded.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Unconfigured {
+    /// Reject the request.
+    Deny,
+    /// Let the request through.
+    Allow,
+}
+
+impl Default for Unconfigured {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+/// Settings given in the VM configuration, which apply regardless of the plugin configuration.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct VmConfiguration {
+    unconfigured: Option<Unconfigured>,
+}
+
+impl TryFrom<&[u8]> for VmConfiguration {
+    type Error = serde_json::Error;
+
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
+        serde_json::from_slice(buf)
+    }
+}
+
+impl VmConfiguration {
+    pub fn unconfigured(&self) -> Unconfigured {
+        self.unconfigured.unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct JWT {
     exp: u64,
@@ -536,6 +819,8 @@ mod test {
                     timeout: core::time::Duration::from_millis(5000),
                 },
                 token: "atoken".into(),
+                environment: None,
+                refresh_interval: None,
             }),
             backend: Some(Backend {
                 name: Some("backend-name".into()),
@@ -545,12 +830,32 @@ mod test {
                     timeout: core::time::Duration::from_millis(5000),
                 },
                 extensions: Some(vec!["no_body".to_string()]),
+                failure_status: Some(504),
+                failure_mode: Some(FailureMode::Degraded),
+                degraded_ttl: None,
+                cache: Some(Cache {
+                    ttl: Some(30),
+                    max_entries: None,
+                }),
+                reporting: Some(Reporting {
+                    flush_interval: Some(5),
+                    max_batch_size: None,
+                }),
             }),
             services: Some(vec![Service {
                 id: "2555417834780".into(),
                 token: "service_token".into(),
                 valid_apps: None,
+                responses: None,
+                failure_mode: Some(FailureMode::Open),
+                cache_ttl: None,
+                unmatched: Some(Unmatched::Report(Usage {
+                    name: "Hits".into(),
+                    delta: 1,
+                })),
                 authorities: vec!["0.0.0.0:8080".into(), "0.0.0.0:8443".into()],
+                ignore_port: None,
+                selector: None,
                 credentials: vec![Parameter::<String> {
                     other: HashMap::new(),
                     kind: ApplicationKind::OIDC,
@@ -594,11 +899,81 @@ mod test {
                         name: "Hits".into(),
                         delta: 1,
                     }],
+                    last: None,
+                    position: None,
                 }],
             }]),
+            responses: Some(Responses {
+                limits_exceeded: Some(DenyResponse {
+                    status: None,
+                    headers: Some(vec![("content-type".into(), "application/json".into())]),
+                    body: Some(r#"{"error": "{reason}", "request_id": "{request_id}"}"#.into()),
+                }),
+                ..Default::default()
+            }),
         }
     }
 
+    #[test]
+    fn it_applies_mapping_rules_by_position_until_last() {
+        let configuration = serde_json::from_str::<Configuration>(
+            r#"{ "services": [{
+                "id": "2555417834780",
+                "token": "service_token",
+                "authorities": ["web"],
+                "credentials": [],
+                "mapping_rules": [
+                  { "method": "get", "pattern": "/", "usages": [{ "name": "hits", "delta": 1 }] },
+                  { "method": "GET", "pattern": "/products", "last": true,
+                    "usages": [{ "name": "products", "delta": 1 }] },
+                  { "method": "get", "pattern": "/products/{id}", "position": 1,
+                    "usages": [{ "name": "product", "delta": 1 }] }
+                ]
+            }] }"#,
+        )
+        .unwrap();
+        let service = &configuration.services().unwrap()[0];
+        let index = MatcherIndex::new(&configuration);
+        let matched = |method, path| {
+            index
+                .matching_rules(0, service, method, path, None)
+                .into_iter()
+                .map(|rule| rule.usages()[0].name())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(matched("GET", "/"), vec!["hits"]);
+        assert_eq!(matched("GET", "/products"), vec!["hits", "products"]);
+        assert_eq!(
+            matched("GET", "/products/1"),
+            vec!["product", "hits", "products"]
+        );
+        assert!(matched("POST", "/products").is_empty());
+    }
+
+    #[test]
+    fn it_parses_unmatched_policies() {
+        let parse = |unmatched| serde_json::from_str::<Unmatched>(unmatched).unwrap();
+        assert_eq!(parse(r#""deny""#), Unmatched::Deny);
+        assert_eq!(parse(r#""allow""#), Unmatched::Allow);
+        assert_eq!(
+            parse(r#"{ "report": { "name": "unmatched", "delta": 1 } }"#),
+            Unmatched::Report(Usage {
+                name: "unmatched".into(),
+                delta: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_vm_configurations() {
+        let vm_configuration =
+            VmConfiguration::try_from(r#"{ "unconfigured": "allow" }"#.as_bytes()).unwrap();
+        assert_eq!(vm_configuration.unconfigured(), Unconfigured::Allow);
+        let vm_configuration = VmConfiguration::try_from("{}".as_bytes()).unwrap();
+        assert_eq!(vm_configuration.unconfigured(), Unconfigured::Deny);
+    }
+
     #[test]
     fn print_config() {
         let config = get_config();
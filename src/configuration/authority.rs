@@ -0,0 +1,100 @@
+/// How an authority entry of a service matched a request, ordered by precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum AuthorityMatch {
+    // length of the suffix matched by the wildcard, with longer ones being more specific
+    Wildcard(usize),
+    Exact,
+}
+
+/// Splits the port off an authority, taking care of IPv6 literals such as `[::1]:8080`.
+pub(crate) fn split_port(authority: &str) -> (&str, Option<&str>) {
+    match authority.rfind(':') {
+        Some(idx)
+            if !authority[idx + 1..].is_empty()
+                && authority[idx + 1..].bytes().all(|b| b.is_ascii_digit())
+                && (!authority.starts_with('[') || authority[..idx].ends_with(']')) =>
+        {
+            (&authority[..idx], Some(&authority[idx + 1..]))
+        }
+        _ => (authority, None),
+    }
+}
+
+/// Matches an authority entry against the authority of a request.
+///
+/// Entries are either exact, or start with `*` to match any host ending with the rest of
+/// the entry, such as `*.apps.example.com`, with `*` alone matching any host. Hosts are
+/// compared case-insensitively. Ports have to be equal, being both absent or the same,
+/// unless `ignore_port` is set, in which case they are not taken into account at all.
+pub(crate) fn match_authority(
+    entry: &str,
+    authority: &str,
+    ignore_port: bool,
+) -> Option<AuthorityMatch> {
+    let (entry_host, entry_port) = split_port(entry);
+    let (host, port) = split_port(authority);
+    if !ignore_port && entry_port != port {
+        return None;
+    }
+
+    match entry_host.strip_prefix('*') {
+        Some(suffix) => {
+            let matches = host.len() > suffix.len()
+                && host.is_char_boundary(host.len() - suffix.len())
+                && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix);
+            if matches {
+                Some(AuthorityMatch::Wildcard(suffix.len()))
+            } else {
+                None
+            }
+        }
+        None if host.eq_ignore_ascii_case(entry_host) => Some(AuthorityMatch::Exact),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_matches_exact_and_wildcard_authorities() {
+        assert_eq!(
+            match_authority("web.app", "Web.App", false),
+            Some(AuthorityMatch::Exact)
+        );
+        assert_eq!(match_authority("web.app", "web.app:8080", false), None);
+        assert_eq!(
+            match_authority("*.apps.example.com", "foo.bar.apps.example.com", false),
+            Some(AuthorityMatch::Wildcard(17))
+        );
+        assert_eq!(
+            match_authority("*.apps.example.com", "apps.example.com", false),
+            None
+        );
+        assert_eq!(
+            match_authority("*", "anything", false),
+            Some(AuthorityMatch::Wildcard(0))
+        );
+        assert!(AuthorityMatch::Exact > AuthorityMatch::Wildcard(17));
+        assert!(AuthorityMatch::Wildcard(17) > AuthorityMatch::Wildcard(4));
+    }
+
+    #[test]
+    fn it_optionally_ignores_ports() {
+        assert_eq!(match_authority("0.0.0.0:8080", "0.0.0.0:8443", false), None);
+        assert_eq!(
+            match_authority("0.0.0.0:8080", "0.0.0.0:8443", true),
+            Some(AuthorityMatch::Exact)
+        );
+        assert_eq!(
+            match_authority("*.example.com", "foo.example.com:443", true),
+            Some(AuthorityMatch::Wildcard(12))
+        );
+        assert_eq!(
+            match_authority("[::1]", "[::1]:8080", true),
+            Some(AuthorityMatch::Exact)
+        );
+        assert_eq!(match_authority("[::1]", "[::1]:8080", false), None);
+    }
+}
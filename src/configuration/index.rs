@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+
+use super::{
+    match_authority, split_port, AuthorityMatch, Configuration, MappingRule, Pattern, Service,
+};
+
+// Byte trie of the literal prefixes of mapping rule patterns.
+#[derive(Debug, Default)]
+struct Node {
+    // rules, by precedence, whose literal prefix ends here
+    rules: Vec<usize>,
+    children: HashMap<u8, Node>,
+}
+
+impl Node {
+    fn insert(&mut self, prefix: &str, rule: usize) {
+        let mut node = self;
+        for b in prefix.bytes() {
+            node = node.children.entry(b).or_default();
+        }
+        node.rules.push(rule);
+    }
+
+    // Collects the rules whose literal prefix is a prefix of the path.
+    fn collect(&self, path: &str, rules: &mut Vec<usize>) {
+        let mut node = self;
+        rules.extend_from_slice(node.rules.as_slice());
+        for b in path.bytes() {
+            node = match node.children.get(&b) {
+                Some(node) => node,
+                None => break,
+            };
+            rules.extend_from_slice(node.rules.as_slice());
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    // index of the rule in the service mapping rules
+    rule: usize,
+    pattern: Pattern,
+    last: bool,
+}
+
+// Mapping rules of a service compiled for lookups by method and path.
+#[derive(Debug, Default)]
+struct RuleIndex {
+    // in order of precedence
+    rules: Vec<CompiledRule>,
+    // uppercase methods and their tries
+    methods: Vec<(String, Node)>,
+}
+
+impl RuleIndex {
+    fn new(service: &Service) -> Self {
+        let mut mapping_rules = service
+            .mapping_rules()
+            .iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        // by position, lowest first, and then the rules without one, with the stable sort
+        // keeping the declaration order among rules with equal or no positions
+        mapping_rules.sort_by_key(|(_, rule)| (rule.position().is_none(), rule.position()));
+
+        let mut index = Self::default();
+        for (idx, rule) in mapping_rules {
+            let pattern = match rule.pattern().parse::<Pattern>() {
+                Ok(pattern) => pattern,
+                Err(e) => {
+                    log::warn!(
+                        "ignoring invalid mapping rule pattern {} of service {}: {}",
+                        rule.pattern(),
+                        service.id(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let method = rule.method().to_ascii_uppercase();
+            let node = match index.methods.iter().position(|(m, _)| *m == method) {
+                Some(pos) => &mut index.methods[pos].1,
+                None => {
+                    index.methods.push((method, Node::default()));
+                    &mut index.methods.last_mut().unwrap().1
+                }
+            };
+            node.insert(pattern.literal_prefix(), index.rules.len());
+            index.rules.push(CompiledRule {
+                rule: idx,
+                pattern,
+                last: rule.last(),
+            });
+        }
+
+        index
+    }
+
+    fn matching_rules(&self, method: &str, path: &str, query: Option<&str>) -> Vec<usize> {
+        let node = match self
+            .methods
+            .iter()
+            .find(|(m, _)| m.eq_ignore_ascii_case(method))
+        {
+            Some((_, node)) => node,
+            None => return vec![],
+        };
+
+        let mut candidates = Vec::new();
+        node.collect(path, &mut candidates);
+        candidates.sort_unstable();
+
+        let mut matched = Vec::new();
+        for candidate in candidates {
+            let compiled = &self.rules[candidate];
+            if compiled.pattern.matches(path, query) {
+                matched.push(compiled.rule);
+                if compiled.last {
+                    break;
+                }
+            }
+        }
+
+        matched
+    }
+}
+
+/// Lookup structures built from a configuration, so that finding the service and mapping
+/// rules of a request does not take longer with the number of services and rules.
+///
+/// Services are referred to by their position in the configuration the index was built from.
+#[derive(Debug, Default)]
+pub(crate) struct MatcherIndex {
+    // services by lowercase authority
+    exact: HashMap<String, Vec<usize>>,
+    // services ignoring ports by lowercase host
+    exact_any_port: HashMap<String, Vec<usize>>,
+    // wildcard authorities, whether they ignore ports and their services, checked one by one
+    wildcards: Vec<(String, bool, usize)>,
+    rules: Vec<RuleIndex>,
+}
+
+impl MatcherIndex {
+    pub fn new(configuration: &Configuration) -> Self {
+        let mut index = Self::default();
+        let services = match configuration.services() {
+            Some(services) => services,
+            None => return index,
+        };
+
+        for (idx, service) in services.iter().enumerate() {
+            for authority in service.authorities() {
+                if authority.starts_with('*') {
+                    index
+                        .wildcards
+                        .push((authority.clone(), service.ignore_port(), idx));
+                } else if service.ignore_port() {
+                    let (host, _) = split_port(authority);
+                    index
+                        .exact_any_port
+                        .entry(host.to_ascii_lowercase())
+                        .or_default()
+                        .push(idx);
+                } else {
+                    index
+                        .exact
+                        .entry(authority.to_ascii_lowercase())
+                        .or_default()
+                        .push(idx);
+                }
+            }
+            index.rules.push(RuleIndex::new(service));
+        }
+
+        index
+    }
+
+    /// Returns the services with authorities matching a request authority, by position, along
+    /// with the best match of each.
+    pub fn authority_matches(&self, authority: &str) -> Vec<(usize, AuthorityMatch)> {
+        let mut matches = HashMap::<usize, AuthorityMatch>::new();
+        let mut add = |idx: usize, m: AuthorityMatch| {
+            let best = matches.entry(idx).or_insert(m);
+            if m > *best {
+                *best = m;
+            }
+        };
+
+        let lowercase = authority.to_ascii_lowercase();
+        let (host, _) = split_port(lowercase.as_str());
+        let exact = self.exact.get(lowercase.as_str()).into_iter().flatten();
+        let exact_any_port = self.exact_any_port.get(host).into_iter().flatten();
+        for &idx in exact.chain(exact_any_port) {
+            add(idx, AuthorityMatch::Exact);
+        }
+        for (entry, ignore_port, idx) in self.wildcards.iter() {
+            if let Some(m) = match_authority(entry, authority, *ignore_port) {
+                add(*idx, m);
+            }
+        }
+
+        let mut matches = matches.into_iter().collect::<Vec<_>>();
+        // in the order services were declared
+        matches.sort_unstable_by_key(|&(idx, _)| idx);
+        matches
+    }
+
+    /// Returns the mapping rules of the service at a position matching a request, in order
+    /// of precedence.
+    ///
+    /// Rules with a `position` go first, lowest first, followed by the rest in the order
+    /// they were declared. Evaluation stops at the first matching rule marked as `last`.
+    pub fn matching_rules<'a>(
+        &self,
+        idx: usize,
+        service: &'a Service,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+    ) -> Vec<&'a MappingRule> {
+        self.rules
+            .get(idx)
+            .map(|rules| rules.matching_rules(method, path, query))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|rule| service.mapping_rules().get(rule))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn configuration() -> Configuration {
+        serde_json::from_str(
+            r#"{ "services": [
+              { "id": "1", "token": "t", "authorities": ["web.app", "*.example.com"],
+                "mapping_rules": [
+                  { "method": "get", "pattern": "/", "usages": [{ "name": "hits", "delta": 1 }] },
+                  { "method": "GET", "pattern": "/books/{id}$", "last": true,
+                    "usages": [{ "name": "book", "delta": 1 }] },
+                  { "method": "GET", "pattern": "/books?sort={x}", "position": 1,
+                    "usages": [{ "name": "sorted", "delta": 1 }] },
+                  { "method": "POST", "pattern": "/books", "usages": [{ "name": "new", "delta": 1 }] },
+                  { "method": "GET", "pattern": "/bo", "usages": [{ "name": "bo", "delta": 1 }] }
+                ] },
+              { "id": "2", "token": "t", "authorities": ["Api.Example.com:8080"], "ignore_port": true }
+            ] }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_finds_services_by_authority() {
+        let index = MatcherIndex::new(&configuration());
+
+        assert_eq!(
+            index.authority_matches("WEB.app"),
+            vec![(0, AuthorityMatch::Exact)]
+        );
+        assert_eq!(
+            index.authority_matches("api.example.com"),
+            vec![
+                (0, AuthorityMatch::Wildcard(12)),
+                (1, AuthorityMatch::Exact)
+            ]
+        );
+        assert_eq!(
+            index.authority_matches("api.example.com:443"),
+            vec![(1, AuthorityMatch::Exact)]
+        );
+        assert!(index.authority_matches("web.app:80").is_empty());
+    }
+
+    #[test]
+    fn it_finds_matching_rules() {
+        let configuration = configuration();
+        let service = &configuration.services().unwrap()[0];
+        let index = MatcherIndex::new(&configuration);
+
+        let requests = [
+            ("GET", "/", None, vec!["hits"]),
+            (
+                "GET",
+                "/books",
+                Some("sort=asc"),
+                vec!["sorted", "hits", "bo"],
+            ),
+            ("GET", "/books/1", None, vec!["hits", "book"]),
+            ("GET", "/books/1/reviews", None, vec!["hits", "bo"]),
+            ("get", "/bottles", None, vec!["hits", "bo"]),
+            ("POST", "/books/1", None, vec!["new"]),
+            ("DELETE", "/books/1", None, vec![]),
+        ];
+        for (method, path, query, expected) in requests.iter() {
+            let names = index
+                .matching_rules(0, service, method, path, *query)
+                .into_iter()
+                .map(|rule| rule.usages()[0].name())
+                .collect::<Vec<_>>();
+            assert_eq!(&names, expected, "{} {} {:?}", method, path, query);
+        }
+    }
+}
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 pub(crate) enum Location {
     Header,
     QueryString,
+    Cookie,
     //Body,
     //Trailer,
     Property,
@@ -22,6 +23,8 @@ pub(crate) enum Decode {
     ProtobufValue,
     #[serde(rename = "json")]
     JsonValue,
+    #[serde(rename = "pairs")]
+    PairsValue,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
@@ -0,0 +1,268 @@
+use core::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub(crate) enum PatternError {
+    #[error("pattern must start with '/'")]
+    NotAbsolute,
+    #[error("unclosed placeholder starting at position {0}")]
+    UnclosedPlaceholder(usize),
+    #[error("empty placeholder at position {0}")]
+    EmptyPlaceholder(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Placeholder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum QueryValue {
+    Literal(String),
+    Placeholder,
+}
+
+/// A 3scale mapping rule pattern.
+///
+/// Paths match as prefixes unless the path part ends with the `$` anchor, and `{name}`
+/// placeholders match one or more characters within a path segment. Parameters in the
+/// query part must all be present in the request, with `{name}` matching any value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Pattern {
+    path: Vec<Token>,
+    // the path has to match entirely rather than as a prefix
+    anchored: bool,
+    query: Vec<(String, QueryValue)>,
+}
+
+// characters APIcast accepts for path placeholders
+fn is_placeholder_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~%!$&'()*+,;=@:".contains(c)
+}
+
+fn placeholder_name(s: &str) -> Option<&str> {
+    s.strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .filter(|name| !name.is_empty())
+}
+
+impl FromStr for Pattern {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, query) = match s.find('?') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+        // the anchor is also accepted at the very end of the pattern
+        let (query, query_anchored) = match query {
+            Some(query) if query.ends_with('$') => (Some(&query[..query.len() - 1]), true),
+            query => (query, false),
+        };
+        let (path, anchored) = match path.strip_suffix('$') {
+            Some(path) => (path, true),
+            None => (path, query_anchored),
+        };
+
+        if !path.starts_with('/') {
+            return Err(PatternError::NotAbsolute);
+        }
+
+        let mut tokens = Vec::new();
+        let mut rest = path;
+        let mut pos = 0;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                tokens.push(Token::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or(PatternError::UnclosedPlaceholder(pos + start))?;
+            if end == 1 {
+                return Err(PatternError::EmptyPlaceholder(pos + start));
+            }
+            tokens.push(Token::Placeholder);
+            pos += start + end + 1;
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Literal(rest.to_string()));
+        }
+
+        let query = query
+            .map(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .map(|(key, value)| {
+                        let value = match placeholder_name(value.as_ref()) {
+                            Some(_) => QueryValue::Placeholder,
+                            None => QueryValue::Literal(value.into_owned()),
+                        };
+                        (key.into_owned(), value)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            path: tokens,
+            anchored,
+            query,
+        })
+    }
+}
+
+impl Pattern {
+    /// Returns the literal text all matching paths start with.
+    pub fn literal_prefix(&self) -> &str {
+        match self.path.first() {
+            Some(Token::Literal(literal)) => literal.as_str(),
+            _ => "",
+        }
+    }
+
+    /// Checks a request path and its query string, if any, against the pattern.
+    pub fn matches(&self, path: &str, query: Option<&str>) -> bool {
+        self.matches_path(path) && self.matches_query(query)
+    }
+
+    // Walks the tokens keeping the set of path offsets they can end at, rather than trying
+    // each length of each placeholder, so that it takes time linear in the path length for
+    // every token however many placeholders there are.
+    fn matches_path(&self, path: &str) -> bool {
+        let path = path.as_bytes();
+        let mut reached = vec![false; path.len() + 1];
+        reached[0] = true;
+
+        for token in self.path.iter() {
+            let mut next = vec![false; path.len() + 1];
+            match token {
+                Token::Literal(literal) => {
+                    let literal = literal.as_bytes();
+                    for (start, _) in reached.iter().enumerate().filter(|(_, &r)| r) {
+                        if path[start..].starts_with(literal) {
+                            next[start + literal.len()] = true;
+                        }
+                    }
+                }
+                Token::Placeholder => {
+                    // whether a reached offset starts a run of placeholder characters up to
+                    // here, which are all ASCII so that offsets stay at character boundaries
+                    let mut in_run = false;
+                    for (end, ends) in next.iter_mut().enumerate() {
+                        *ends = in_run;
+                        in_run = (in_run || reached[end])
+                            && path
+                                .get(end)
+                                .map_or(false, |&b| is_placeholder_char(b as char));
+                    }
+                }
+            }
+            reached = next;
+        }
+
+        if self.anchored {
+            reached[path.len()]
+        } else {
+            reached.contains(&true)
+        }
+    }
+
+    fn matches_query(&self, query: Option<&str>) -> bool {
+        if self.query.is_empty() {
+            return true;
+        }
+
+        let params = query
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        self.query.iter().all(|(key, value)| {
+            params.iter().any(|(k, v)| {
+                k == key
+                    && match value {
+                        QueryValue::Placeholder => true,
+                        QueryValue::Literal(literal) => v == literal,
+                    }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn it_matches_prefixes_and_anchors() {
+        let p = pattern("/foo");
+        assert!(p.matches("/foo", None));
+        assert!(p.matches("/foo/bar", None));
+        assert!(p.matches("/foobar", None));
+        assert!(!p.matches("/", None));
+        assert!(!p.matches("/fo", None));
+
+        let p = pattern("/foo$");
+        assert!(p.matches("/foo", None));
+        assert!(!p.matches("/foo/bar", None));
+
+        assert!(pattern("/").matches("/anything", None));
+    }
+
+    #[test]
+    fn it_matches_placeholders() {
+        let p = pattern("/products/{id}/reviews$");
+        assert!(p.matches("/products/123/reviews", None));
+        assert!(p.matches("/products/a-b.c~d/reviews", None));
+        assert!(!p.matches("/products//reviews", None));
+        assert!(!p.matches("/products/1/2/reviews", None));
+
+        let p = pattern("/{name}.json");
+        assert!(p.matches("/books.json", None));
+        assert!(p.matches("/books.old.json", None));
+        assert!(!p.matches("/books.xml", None));
+    }
+
+    #[test]
+    fn it_matches_many_placeholders_in_linear_time() {
+        let p = pattern("/{a}-{b}-{c}-{d}-{e}-{f}-{g}-{h}$");
+        assert!(p.matches("/1-2-3-4-5-6-7-8", None));
+        assert!(p.matches("/1-2-3-4-5-6-7-8-9", None));
+        assert!(!p.matches("/1-2-3-4-5-6-7", None));
+
+        let path = format!("/{}/", "a-".repeat(10_000));
+        assert!(!p.matches(path.as_str(), None));
+        assert!(pattern("/{a}-{b}-{c}-{d}").matches(path.as_str(), None));
+    }
+
+    #[test]
+    fn it_matches_query_parameters() {
+        let p = pattern("/foo?bar={x}&baz=1");
+        assert!(p.matches("/foo", Some("baz=1&bar=something&other=2")));
+        assert!(p.matches("/foo/more", Some("bar=&baz=1")));
+        assert!(!p.matches("/foo", Some("bar=something&baz=2")));
+        assert!(!p.matches("/foo", Some("baz=1")));
+        assert!(!p.matches("/foo", None));
+
+        let p = pattern("/foo$?bar=a%20b");
+        assert!(p.matches("/foo", Some("bar=a+b")));
+        assert!(!p.matches("/foo/more", Some("bar=a+b")));
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        assert_eq!("foo".parse::<Pattern>(), Err(PatternError::NotAbsolute));
+        assert_eq!(
+            "/foo/{id".parse::<Pattern>(),
+            Err(PatternError::UnclosedPlaceholder(5))
+        );
+        assert_eq!(
+            "/foo/{}".parse::<Pattern>(),
+            Err(PatternError::EmptyPlaceholder(5))
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{ApplicationKind, Location, LocationInfo, MappingRule, Parameter, Usage, ValueDnF};
+
+/// A mapping rule as defined in the 3scale admin portal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ProxyRule {
+    http_method: String,
+    pattern: String,
+    metric_system_name: String,
+    delta: i64,
+    position: Option<u32>,
+    last: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Proxy {
+    #[serde(default)]
+    hosts: Vec<String>,
+    credentials_location: Option<String>,
+    auth_user_key: Option<String>,
+    auth_app_id: Option<String>,
+    auth_app_key: Option<String>,
+    #[serde(default)]
+    proxy_rules: Vec<ProxyRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ProxyConfigContent {
+    backend_version: String,
+    proxy: Proxy,
+}
+
+/// The configuration of a service as published by 3scale System for an environment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ProxyConfig {
+    version: u64,
+    environment: String,
+    content: ProxyConfigContent,
+}
+
+// the envelope System sends the configuration in
+#[derive(Debug, Deserialize)]
+struct ProxyConfigResponse {
+    proxy_config: ProxyConfig,
+}
+
+impl ProxyConfig {
+    /// Parses the body of a System response for the latest proxy configuration.
+    pub fn from_response(body: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice::<ProxyConfigResponse>(body).map(|response| response.proxy_config)
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn environment(&self) -> &str {
+        self.environment.as_str()
+    }
+
+    pub fn backend_version(&self) -> &str {
+        self.content.backend_version.as_str()
+    }
+
+    pub fn authorities(&self) -> Vec<String> {
+        self.content.proxy.hosts.clone()
+    }
+
+    pub fn mapping_rules(&self) -> Vec<MappingRule> {
+        self.content
+            .proxy
+            .proxy_rules
+            .iter()
+            .map(|rule| MappingRule {
+                method: rule.http_method.clone(),
+                pattern: rule.pattern.clone(),
+                usages: vec![Usage {
+                    name: rule.metric_system_name.clone(),
+                    delta: rule.delta,
+                }],
+                last: rule.last,
+                position: rule.position,
+            })
+            .collect()
+    }
+
+    /// Returns where to find credentials according to the backend version of the service.
+    ///
+    /// Nothing is returned for OpenID Connect services, nor for credentials sent as HTTP
+    /// Basic authentication, which have to be configured in the plugin instead.
+    pub fn credentials(&self) -> Vec<Parameter<String>> {
+        let proxy = &self.content.proxy;
+        let location = match proxy.credentials_location.as_deref() {
+            Some("headers") => Location::Header,
+            Some("query") | None => Location::QueryString,
+            Some(other) => {
+                log::warn!("unsupported credentials location {} in proxy config", other);
+                return vec![];
+            }
+        };
+        let parameter = |kind, key: Option<&String>, default: &str| Parameter {
+            locations: vec![LocationInfo {
+                location: location.clone(),
+                path: None,
+                value_dnf: ValueDnF {
+                    decode: None,
+                    format: None,
+                },
+            }],
+            kind,
+            keys: vec![key.map(String::as_str).unwrap_or(default).to_string()],
+            other: HashMap::new(),
+        };
+
+        match self.backend_version() {
+            "1" => vec![parameter(
+                ApplicationKind::UserKey,
+                proxy.auth_user_key.as_ref(),
+                "user_key",
+            )],
+            "2" => vec![
+                parameter(ApplicationKind::AppId, proxy.auth_app_id.as_ref(), "app_id"),
+                parameter(
+                    ApplicationKind::AppKey,
+                    proxy.auth_app_key.as_ref(),
+                    "app_key",
+                ),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RESPONSE: &str = r#"{
+        "proxy_config": {
+          "id": 1234,
+          "version": 3,
+          "environment": "production",
+          "content": {
+            "id": 2555417834780,
+            "backend_version": "2",
+            "proxy": {
+              "hosts": ["api.example.com"],
+              "credentials_location": "headers",
+              "auth_app_id": "x-app-id",
+              "auth_app_key": "x-app-key",
+              "proxy_rules": [
+                {
+                  "http_method": "GET",
+                  "pattern": "/books",
+                  "metric_system_name": "books",
+                  "delta": 2,
+                  "position": 1,
+                  "last": true
+                }
+              ]
+            }
+          }
+        }
+    }"#;
+
+    #[test]
+    fn it_converts_system_proxy_configs() {
+        let config = ProxyConfig::from_response(RESPONSE.as_bytes()).unwrap();
+        assert_eq!(config.version(), 3);
+        assert_eq!(config.authorities(), vec!["api.example.com".to_string()]);
+
+        let rules = config.mapping_rules();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].method(), "GET");
+        assert_eq!(rules[0].usages()[0].name(), "books");
+        assert_eq!(rules[0].usages()[0].delta(), 2);
+        assert!(rules[0].last());
+        assert_eq!(rules[0].position(), Some(1));
+
+        let credentials = config.credentials();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].kind(), ApplicationKind::AppId);
+        assert_eq!(credentials[0].keys(), &vec!["x-app-id".to_string()]);
+        assert_eq!(credentials[0].locations()[0].location(), &Location::Header);
+        assert_eq!(credentials[1].kind(), ApplicationKind::AppKey);
+    }
+}
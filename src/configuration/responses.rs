@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+/// A response sent downstream when rejecting a request.
+///
+/// The body is a template in which `{status}`, `{reason}` and `{request_id}` are replaced
+/// with their values for the rejected request. When the `content-type` header is a JSON
+/// media type the replaced values are escaped as JSON string contents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct DenyResponse {
+    pub status: Option<u32>,
+    pub headers: Option<Vec<(String, String)>>,
+    pub body: Option<String>,
+}
+
+impl DenyResponse {
+    pub fn status(&self) -> Option<u32> {
+        self.status
+    }
+
+    pub fn headers(&self) -> Option<&Vec<(String, String)>> {
+        self.headers.as_ref()
+    }
+
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.as_ref().and_then(|headers| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+                .map(|(_, value)| value.as_str())
+        })
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.content_type()
+            .and_then(|ct| ct.split(';').next())
+            .map(|mime| {
+                let mime = mime.trim();
+                mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json")
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Responses for each class of failure, falling back to built-in defaults when not set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct Responses {
+    pub no_service_matched: Option<DenyResponse>,
+    pub no_mapping_rule_matched: Option<DenyResponse>,
+    pub credentials_missing: Option<DenyResponse>,
+    pub auth_denied: Option<DenyResponse>,
+    pub limits_exceeded: Option<DenyResponse>,
+    pub backend_unavailable: Option<DenyResponse>,
+}
+
+impl Responses {
+    pub fn no_service_matched(&self) -> Option<&DenyResponse> {
+        self.no_service_matched.as_ref()
+    }
+
+    pub fn no_mapping_rule_matched(&self) -> Option<&DenyResponse> {
+        self.no_mapping_rule_matched.as_ref()
+    }
+
+    pub fn credentials_missing(&self) -> Option<&DenyResponse> {
+        self.credentials_missing.as_ref()
+    }
+
+    pub fn auth_denied(&self) -> Option<&DenyResponse> {
+        self.auth_denied.as_ref()
+    }
+
+    pub fn limits_exceeded(&self) -> Option<&DenyResponse> {
+        self.limits_exceeded.as_ref()
+    }
+
+    pub fn backend_unavailable(&self) -> Option<&DenyResponse> {
+        self.backend_unavailable.as_ref()
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+use super::AuthorityMatch;
+
+/// A header a request must have to be matched to a service, with a given value if set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HeaderPredicate {
+    name: String,
+    value: Option<String>,
+}
+
+impl HeaderPredicate {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
+
+/// Conditions, besides the authority, for requests to be matched to a service.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Selector {
+    path_prefix: Option<String>,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<HeaderPredicate>>,
+}
+
+/// How specifically a service matched a request, with greater values taking precedence.
+///
+/// The authority is compared first, then the length of the path prefix, the number of
+/// headers and lastly whether methods were required.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ServiceMatch {
+    authority: AuthorityMatch,
+    path_prefix: usize,
+    headers: usize,
+    methods: bool,
+}
+
+// Prefixes only match whole path segments, so that `/v1` matches `/v1/books` but not `/v10`.
+fn match_path_prefix(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .map(|rest| prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(false)
+}
+
+impl Selector {
+    pub fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    pub fn methods(&self) -> Option<&Vec<String>> {
+        self.methods.as_ref()
+    }
+
+    pub fn headers(&self) -> Option<&Vec<HeaderPredicate>> {
+        self.headers.as_ref()
+    }
+
+    /// Checks a request, looking up its headers with `header`, against the selector, with
+    /// the authority having been matched already.
+    pub fn matches<'h>(
+        &self,
+        authority: AuthorityMatch,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<&'h str>,
+    ) -> Option<ServiceMatch> {
+        if let Some(prefix) = self.path_prefix() {
+            if !match_path_prefix(prefix, path) {
+                return None;
+            }
+        }
+
+        if let Some(methods) = self.methods() {
+            if !methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+                return None;
+            }
+        }
+
+        let headers = self.headers().map(Vec::as_slice).unwrap_or_default();
+        let headers_match = headers.iter().all(|predicate| {
+            let actual = header(predicate.name());
+            match predicate.value() {
+                Some(expected) => actual == Some(expected),
+                None => actual.is_some(),
+            }
+        });
+        if !headers_match {
+            return None;
+        }
+
+        Some(ServiceMatch {
+            authority,
+            path_prefix: self.path_prefix().map(str::len).unwrap_or(0),
+            headers: headers.len(),
+            methods: self.methods.is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn selector(json: &str) -> Selector {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn it_matches_requests_against_selectors() {
+        let s = selector(
+            r#"{ "path_prefix": "/v1", "methods": ["get", "post"],
+                 "headers": [{ "name": "x-api-version", "value": "2" }, { "name": "x-tenant" }] }"#,
+        );
+        let header = |name: &str| match name {
+            "x-api-version" => Some("2"),
+            "x-tenant" => Some("acme"),
+            _ => None,
+        };
+        let exact = AuthorityMatch::Exact;
+
+        assert!(s.matches(exact, "GET", "/v1/books", header).is_some());
+        assert!(s.matches(exact, "POST", "/v1", header).is_some());
+        assert!(s.matches(exact, "GET", "/v10", header).is_none());
+        assert!(s.matches(exact, "DELETE", "/v1/books", header).is_none());
+        let no_tenant = |name: &str| header(name).filter(|_| name != "x-tenant");
+        assert!(s.matches(exact, "GET", "/v1/books", no_tenant).is_none());
+        let other_version = |name: &str| header(name).map(|_| "1");
+        assert!(s
+            .matches(exact, "GET", "/v1/books", other_version)
+            .is_none());
+    }
+
+    #[test]
+    fn it_ranks_more_specific_matches_higher() {
+        let header = |_: &str| Some("2");
+        let any = Selector::default()
+            .matches(AuthorityMatch::Exact, "GET", "/v1/books", header)
+            .unwrap();
+        let prefixed = selector(r#"{ "path_prefix": "/v1/" }"#)
+            .matches(AuthorityMatch::Exact, "GET", "/v1/books", header)
+            .unwrap();
+        let longer = selector(r#"{ "path_prefix": "/v1/books" }"#)
+            .matches(AuthorityMatch::Exact, "GET", "/v1/books", header)
+            .unwrap();
+        let wildcard = selector(r#"{ "path_prefix": "/v1/books" }"#)
+            .matches(AuthorityMatch::Wildcard(4), "GET", "/v1/books", header)
+            .unwrap();
+
+        assert!(prefixed > any);
+        assert!(longer > prefixed);
+        assert!(any > wildcard);
+    }
+}
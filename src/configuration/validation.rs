@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use thiserror::Error;
+
+use super::{ApplicationKind, Configuration, Location, Pattern, Service, Unmatched, Usage};
+
+const HTTP_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// A semantic problem in a configuration, along with the JSON path of the offending value.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{path}: {message}")]
+pub(crate) struct ValidationError {
+    pub path: String,
+    pub message: String,
+}
+
+fn is_http_method(method: &str) -> bool {
+    HTTP_METHODS.iter().any(|m| m.eq_ignore_ascii_case(method))
+}
+
+#[derive(Debug, Default)]
+struct Errors(Vec<ValidationError>);
+
+impl Errors {
+    fn add(&mut self, path: String, message: impl ToString) {
+        self.0.push(ValidationError {
+            path,
+            message: message.to_string(),
+        });
+    }
+
+    fn check_usage(&mut self, path: String, usage: &Usage) {
+        if usage.name().is_empty() {
+            self.add(format!("{}.name", path), "metric name is empty");
+        }
+        if usage.delta() <= 0 {
+            self.add(
+                format!("{}.delta", path),
+                format!("delta must be positive, got {}", usage.delta()),
+            );
+        }
+    }
+
+    // Authorities, credentials and mapping rules can be left for System to fill in.
+    fn check_service(&mut self, path: &str, service: &Service, with_system: bool) {
+        if service.authorities().is_empty() && !with_system {
+            self.add(format!("{}.authorities", path), "no authorities");
+        }
+
+        if service.credentials.is_empty() && !with_system {
+            self.add(format!("{}.credentials", path), "no credentials");
+        }
+        for (idx, param) in service.credentials.iter().enumerate() {
+            let path = format!("{}.credentials[{}]", path, idx);
+            if param.keys().is_empty() {
+                self.add(format!("{}.keys", path), "no keys");
+            }
+            if param.locations().is_empty() {
+                self.add(format!("{}.locations", path), "no locations");
+            }
+            for (idx, location) in param.locations().iter().enumerate() {
+                if *location.location() == Location::Property && location.path().is_none() {
+                    self.add(
+                        format!("{}.locations[{}].path", path, idx),
+                        "property locations require a path",
+                    );
+                }
+            }
+        }
+        let has_kind = |kind| service.credentials.iter().any(|param| param.kind() == kind);
+        if has_kind(ApplicationKind::AppKey) && !has_kind(ApplicationKind::AppId) {
+            let idx = service
+                .credentials
+                .iter()
+                .position(|param| param.kind() == ApplicationKind::AppKey)
+                .unwrap_or_default();
+            self.add(
+                format!("{}.credentials[{}].kind", path, idx),
+                "app_key credentials require app_id credentials",
+            );
+        }
+
+        for (idx, rule) in service.mapping_rules().iter().enumerate() {
+            let path = format!("{}.mapping_rules[{}]", path, idx);
+            if !is_http_method(rule.method()) {
+                self.add(
+                    format!("{}.method", path),
+                    format!("unknown HTTP method {}", rule.method()),
+                );
+            }
+            if let Err(e) = rule.pattern().parse::<Pattern>() {
+                self.add(format!("{}.pattern", path), e);
+            }
+            for (idx, usage) in rule.usages().iter().enumerate() {
+                self.check_usage(format!("{}.usages[{}]", path, idx), usage);
+            }
+        }
+
+        if let Some(methods) = service.selector().and_then(|selector| selector.methods()) {
+            for (idx, method) in methods.iter().enumerate() {
+                if !is_http_method(method) {
+                    self.add(
+                        format!("{}.selector.methods[{}]", path, idx),
+                        format!("unknown HTTP method {}", method),
+                    );
+                }
+            }
+        }
+
+        if let Some(Unmatched::Report(usage)) = service.unmatched() {
+            self.check_usage(format!("{}.unmatched.report", path), usage);
+        }
+    }
+}
+
+impl Configuration {
+    /// Checks the configuration for problems that parsing it does not catch, returning all of
+    /// them if any.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Errors::default();
+        let with_system = self.system().is_some();
+        let mut ids = HashMap::new();
+
+        for (idx, service) in self.services().into_iter().flatten().enumerate() {
+            let path = format!("$.services[{}]", idx);
+            if let Some(first) = ids.insert(service.id(), idx) {
+                // keep pointing at the first one
+                ids.insert(service.id(), first);
+                errors.add(
+                    format!("{}.id", path),
+                    format!(
+                        "duplicate service id {}, also used by $.services[{}]",
+                        service.id(),
+                        first
+                    ),
+                );
+            }
+            errors.check_service(path.as_str(), service, with_system);
+        }
+
+        if errors.0.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(json: &str) -> Result<(), Vec<String>> {
+        serde_json::from_str::<Configuration>(json)
+            .unwrap()
+            .validate()
+            .map_err(|errors| errors.into_iter().map(|e| e.to_string()).collect())
+    }
+
+    #[test]
+    fn it_accepts_valid_configurations() {
+        let result = validate(
+            r#"{ "services": [{ "id": "1", "token": "t", "authorities": ["web"],
+                 "credentials": [
+                   { "kind": "app_id", "keys": ["app_id"], "locations": [{ "location": "header" }] },
+                   { "kind": "app_key", "keys": ["app_key"], "locations": [{ "location": "header" }] }
+                 ],
+                 "mapping_rules": [
+                   { "method": "get", "pattern": "/", "usages": [{ "name": "hits", "delta": 1 }] }
+                 ] }] }"#,
+        );
+        assert_eq!(result, Ok(()));
+
+        // System fills in what is missing
+        let result = validate(
+            r#"{ "system": { "upstream": { "name": "system", "url": "https://system", "timeout": 5000 },
+                             "token": "t" },
+                 "services": [{ "id": "1", "token": "t" }] }"#,
+        );
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn it_reports_every_error_with_its_path() {
+        let result = validate(
+            r#"{ "services": [
+                 { "id": "1", "token": "t", "authorities": ["web"],
+                   "credentials": [
+                     { "kind": "app_key", "keys": [], "locations": [{ "location": "property" }] }
+                   ],
+                   "mapping_rules": [
+                     { "method": "FETCH", "pattern": "/{}", "usages": [{ "name": "hits", "delta": 0 }] }
+                   ],
+                   "unmatched": { "report": { "name": "unmatched", "delta": -1 } } },
+                 { "id": "1", "token": "t", "authorities": [], "credentials": [] }
+               ] }"#,
+        );
+        assert_eq!(
+            result,
+            Err(vec![
+                "$.services[0].credentials[0].keys: no keys".to_string(),
+                "$.services[0].credentials[0].locations[0].path: property locations require a path"
+                    .to_string(),
+                "$.services[0].credentials[0].kind: app_key credentials require app_id credentials"
+                    .to_string(),
+                "$.services[0].mapping_rules[0].method: unknown HTTP method FETCH".to_string(),
+                "$.services[0].mapping_rules[0].pattern: empty placeholder at position 1"
+                    .to_string(),
+                "$.services[0].mapping_rules[0].usages[0].delta: delta must be positive, got 0"
+                    .to_string(),
+                "$.services[0].unmatched.report.delta: delta must be positive, got -1".to_string(),
+                "$.services[1].id: duplicate service id 1, also used by $.services[0]".to_string(),
+                "$.services[1].authorities: no authorities".to_string(),
+                "$.services[1].credentials: no credentials".to_string(),
+            ])
+        );
+    }
+}
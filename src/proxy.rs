@@ -1,22 +1,225 @@
 mod authrep;
+mod cache;
 mod decode;
+mod metrics;
+mod rejection;
 mod request_headers;
+mod shared;
+mod state;
+mod system;
+#[cfg(test)]
+mod test_backend;
+#[cfg(test)]
+mod test_host;
+
+use core::convert::TryFrom;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 use log::{debug, error, info, warn};
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
 
-use crate::configuration::Configuration;
+use crate::configuration::{
+    Backend, Configuration, FailureMode, MatcherIndex, ProxyConfig, Service, Unconfigured,
+    Unmatched, VmConfiguration,
+};
+use authrep::response::AuthRepResponse;
+use authrep::MatchError;
+use cache::{CacheKey, Entry};
+use metrics::{Counter, Metrics};
+use rejection::Rejection;
+use state::{AppRef, PendingUsage, Report, MAX_PENDING_APPS};
+use system::ProxyConfigs;
+
+// how often pending usage is checked for reporting and cached state is expired
+const TICK_PERIOD_SECS: u64 = 1;
 
 pub(crate) struct HttpAuthThreescale {
     context_id: u32,
-    configuration: Configuration,
+    configuration: Rc<Configuration>,
+    index: Rc<MatcherIndex>,
+    // shared queue of usage to be reported later
+    reports_queue: Option<u32>,
+    metrics: Metrics,
+    // application and usage of the request being authorized by the backend, if any
+    app: Option<AppRef>,
+    usages: HashMap<String, i64>,
 }
 
 impl HttpAuthThreescale {
     pub fn configuration(&self) -> &Configuration {
         &self.configuration
     }
+
+    pub fn index(&self) -> &MatcherIndex {
+        &self.index
+    }
+
+    fn reject(&self, service: Option<&Service>, rejection: Rejection) {
+        let deny_response = service
+            .and_then(Service::responses)
+            .and_then(|responses| rejection.deny_response(responses))
+            .or_else(|| {
+                self.configuration
+                    .responses()
+                    .and_then(|responses| rejection.deny_response(responses))
+            });
+        let backend_failure_status = self
+            .configuration
+            .backend()
+            .map(|backend| backend.failure_status())
+            .unwrap_or(503);
+        rejection.send(self, deny_response, backend_failure_status);
+    }
+
+    // Authorizes the request locally if cached limits allow it, queueing its usage to be
+    // reported later.
+    fn authorize_from_cache(&self, app: &AppRef, usages: &HashMap<String, i64>) -> bool {
+        let reports_queue = match self.reports_queue {
+            Some(reports_queue) => reports_queue,
+            None => return false,
+        };
+        let key = CacheKey::new(app, usages);
+        let now = current_time_secs(self);
+        if !shared::cache_authorize(&key, usages, now) {
+            return false;
+        }
+
+        if !shared::enqueue_report(reports_queue, &(app.clone(), usages.clone())) {
+            debug!("could not queue usage, not using the cache");
+            return false;
+        }
+
+        debug!(
+            "authorized a request of service {} from cache",
+            app.service_id
+        );
+        true
+    }
+
+    // Remembers an authorization from the backend, caching it if the cache is enabled.
+    fn record_authorized(
+        &self,
+        service: Option<&Service>,
+        app: &AppRef,
+        response: &AuthRepResponse,
+        now: u64,
+    ) {
+        let cache = self.configuration.backend().and_then(Backend::cache);
+        let key = CacheKey::new(app, &self.usages);
+        let usage_reports = response
+            .usage_reports()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        if !shared::record_authorized(app, now) {
+            warn!(
+                "could not record the authorization of an application of service {}",
+                app.service_id
+            );
+        }
+
+        if let Some(cache) = cache {
+            let ttl = service
+                .and_then(Service::cache_ttl)
+                .unwrap_or_else(|| cache.ttl());
+            let entry = Entry::new(usage_reports, now, ttl);
+            if !shared::cache_insert(&key, &entry, now, cache.max_entries()) {
+                warn!(
+                    "could not cache the authorization of an application of service {}",
+                    app.service_id
+                );
+            }
+        }
+    }
+
+    fn forget_authorized(&self, app: &AppRef) {
+        let cached = self.configuration.backend().and_then(Backend::cache);
+        let forgotten = shared::forget_authorized(app);
+        let uncached = cached.is_none() || shared::cache_remove(&CacheKey::new(app, &self.usages));
+        if !(uncached && forgotten) {
+            warn!(
+                "could not forget the authorization of an application of service {}",
+                app.service_id
+            );
+        }
+    }
+
+    // Applies the failure mode when the backend could not answer, queueing the usage of
+    // the request for a later report if it is let through.
+    fn allow_on_backend_failure(
+        &self,
+        service: &Service,
+        app: &AppRef,
+        usages: &HashMap<String, i64>,
+    ) -> bool {
+        let backend = self.configuration.backend();
+        let failure_mode = service
+            .failure_mode()
+            .or_else(|| backend.and_then(Backend::failure_mode))
+            .unwrap_or_default();
+
+        let allow = match failure_mode {
+            FailureMode::Closed => false,
+            FailureMode::Open => true,
+            FailureMode::Degraded => {
+                let now = current_time_secs(self);
+                let ttl = backend.map(Backend::degraded_ttl).unwrap_or_default();
+                shared::recently_authorized(app, now, ttl)
+            }
+        };
+        info!(
+            "backend unavailable, failure mode {:?}: {} a request of service {}",
+            failure_mode,
+            if allow { "allowing" } else { "denying" },
+            app.service_id
+        );
+
+        if allow
+            && !self.reports_queue.map_or(false, |reports_queue| {
+                shared::enqueue_report(reports_queue, &(app.clone(), usages.clone()))
+            })
+        {
+            warn!(
+                "could not queue usage, dropping usage of an application of service {}",
+                app.service_id
+            );
+            self.metrics.increment(Counter::UsageDropped);
+        }
+
+        allow
+    }
+}
+
+fn current_time_secs<C: Context>(ctx: &C) -> u64 {
+    ctx.get_current_time()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Handles requests while no plugin configuration is loaded.
+struct UnconfiguredHttpAuthThreescale {
+    context_id: u32,
+    unconfigured: Unconfigured,
+}
+
+impl Context for UnconfiguredHttpAuthThreescale {}
+
+impl HttpContext for UnconfiguredHttpAuthThreescale {
+    fn on_http_request_headers(&mut self, _: usize) -> FilterHeadersStatus {
+        if self.unconfigured == Unconfigured::Allow {
+            debug!(
+                "on_http_request_headers: context_id {}: no configuration loaded, allowing request",
+                self.context_id
+            );
+            return FilterHeadersStatus::Continue;
+        }
+
+        Rejection::NotConfigured.send(self, None, 503);
+        FilterHeadersStatus::StopIteration
+    }
 }
 
 impl HttpContext for HttpAuthThreescale {
@@ -33,22 +236,72 @@ impl HttpContext for HttpAuthThreescale {
 
         let rh = request_headers::RequestHeaders::new(self);
 
-        let (service, kind, app_id, format, usages) = match authrep::authrep(self, &rh) {
+        let (service, app, format, usages) = match authrep::authrep(self, &rh) {
             Err(e) => {
                 error!("error computing authrep {:?}", e);
-                self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                info!("threescale_wasm_auth: 403 sent");
+                let (rejection, service_id) = match e.downcast_ref::<MatchError>() {
+                    Some(MatchError::NoServiceMatched) => (Rejection::NoServiceMatched, None),
+                    Some(MatchError::CredentialsNotFound(service_id))
+                    | Some(MatchError::AppKeyNotFound(service_id)) => {
+                        (Rejection::CredentialsMissing, Some(service_id.as_str()))
+                    }
+                    Some(MatchError::NoMappingRuleMatched) => {
+                        (Rejection::NoMappingRuleMatched, None)
+                    }
+                    None => (Rejection::Denied(None), None),
+                };
+                let service = service_id.and_then(|id| self.configuration.get_service(id));
+                self.reject(service, rejection);
                 return FilterHeadersStatus::StopIteration;
             }
             Ok(params) => params,
         };
+        let usages = match usages {
+            Some(usages) => usages,
+            None => match service.unmatched() {
+                None | Some(Unmatched::Deny) => {
+                    info!(
+                        "threescale_wasm_auth: no mapping rule matched for service {}, denying",
+                        service.id()
+                    );
+                    self.metrics.increment(Counter::UnmatchedDenied);
+                    self.reject(Some(service), Rejection::NoMappingRuleMatched);
+                    return FilterHeadersStatus::StopIteration;
+                }
+                Some(Unmatched::Allow) => {
+                    info!(
+                        "threescale_wasm_auth: no mapping rule matched for service {}, allowing without reporting",
+                        service.id()
+                    );
+                    self.metrics.increment(Counter::UnmatchedAllowed);
+                    return FilterHeadersStatus::Continue;
+                }
+                Some(Unmatched::Report(usage)) => {
+                    info!(
+                        "threescale_wasm_auth: no mapping rule matched for service {}, reporting {} {}",
+                        service.id(),
+                        usage.name(),
+                        usage.delta()
+                    );
+                    self.metrics.increment(Counter::UnmatchedReported);
+                    std::iter::once((usage.name(), usage.delta())).collect()
+                }
+            },
+        };
+        let owned_usages = usages
+            .iter()
+            .map(|(&metric, &delta)| (metric.to_string(), delta))
+            .collect::<HashMap<_, _>>();
 
         if let Some(backend) = backend {
-            let request = match authrep::build_call(service, kind, app_id, format, usages) {
+            if backend.cache().is_some() && self.authorize_from_cache(&app, &owned_usages) {
+                return FilterHeadersStatus::Continue;
+            }
+
+            let request = match authrep::build_call(service, &app, format, usages) {
                 Err(e) => {
                     error!("error computing authrep request {:?}", e);
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    info!("threescale_wasm_auth: 403 sent");
+                    self.reject(Some(service), Rejection::Denied(None));
                     return FilterHeadersStatus::StopIteration;
                 }
                 Ok(request) => request,
@@ -76,8 +329,10 @@ impl HttpContext for HttpAuthThreescale {
                 Ok(call_token) => call_token,
                 Err(e) => {
                     error!("on_http_request_headers: could not dispatch HTTP call to {}: did you create the cluster to do so? - {:#?}", upstream.name(), e);
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    info!("threescale_wasm_auth: 403 sent");
+                    if self.allow_on_backend_failure(service, &app, &owned_usages) {
+                        return FilterHeadersStatus::Continue;
+                    }
+                    self.reject(Some(service), Rejection::BackendUnavailable);
                     return FilterHeadersStatus::StopIteration;
                 }
             };
@@ -86,6 +341,8 @@ impl HttpContext for HttpAuthThreescale {
                 "threescale_wasm_auth: on_http_request_headers: call token is {}",
                 call_token
             );
+            self.app = Some(app);
+            self.usages = owned_usages;
 
             FilterHeadersStatus::StopIteration
         } else {
@@ -95,11 +352,11 @@ impl HttpContext for HttpAuthThreescale {
                 Some(valid_apps) => {
                     debug!(
                         "on_http_request_headers: looking for {} in valid apps",
-                        app_id
+                        app.app_id
                     );
                     if valid_apps
                         .iter()
-                        .find(|&valid_app| app_id == valid_app.as_str())
+                        .find(|&valid_app| app.app_id == valid_app.as_str())
                         .is_some()
                     {
                         // there is currently no provision to check limits nor to report - careful!
@@ -107,15 +364,13 @@ impl HttpContext for HttpAuthThreescale {
                         return FilterHeadersStatus::Continue;
                     } else {
                         debug!("on_http_request_headers: application not found in valid apps list");
-                        self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                        info!("threescale_wasm_auth: 403 sent");
+                        self.reject(Some(service), Rejection::Denied(None));
                         return FilterHeadersStatus::StopIteration;
                     }
                 }
                 None => {
                     debug!("on_http_request_headers: no backend and no valid apps configured");
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    info!("threescale_wasm_auth: 403 sent");
+                    self.reject(Some(service), Rejection::Denied(None));
                     return FilterHeadersStatus::StopIteration;
                 }
             }
@@ -129,44 +384,380 @@ impl HttpContext for HttpAuthThreescale {
 }
 
 impl Context for HttpAuthThreescale {
-    fn on_http_call_response(&mut self, call_token: u32, _: usize, _: usize, _: usize) {
+    fn on_http_call_response(&mut self, call_token: u32, _: usize, body_size: usize, _: usize) {
         info!(
             "threescale_wasm_auth: on_http_call_response: call_token is {}",
             call_token
         );
-        let authorized = self
+        let status = self
             .get_http_call_response_headers()
             .into_iter()
             .find(|(key, _)| key.as_str() == ":status")
-            .map(|(_, value)| value.as_str() == "200")
-            .unwrap_or(false);
+            .and_then(|(_, value)| value.parse::<u32>().ok());
+        let body = self.get_http_call_response_body(0, body_size);
 
-        if authorized {
-            info!("on_http_call_response: authorized {}", call_token);
-            self.resume_http_request();
-        } else {
-            info!("on_http_call_response: forbidden {}", call_token);
-            self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-            info!("threescale_wasm_auth: 403 sent");
+        let now = current_time_secs(self);
+        let app = self.app.as_ref();
+        let service = app.and_then(|app| self.configuration.get_service(&app.service_id));
+
+        let response = body.as_deref().map(AuthRepResponse::try_from);
+
+        match Rejection::from_authrep_response(status, response.as_ref(), now) {
+            None => {
+                info!("on_http_call_response: authorized {}", call_token);
+                if let (Some(app), Some(Ok(response))) = (app, response.as_ref()) {
+                    self.record_authorized(service, app, response, now);
+                }
+                self.resume_http_request();
+            }
+            Some(Rejection::BackendUnavailable)
+                if service
+                    .zip(app)
+                    .map(|(service, app)| self.allow_on_backend_failure(service, app, &self.usages))
+                    .unwrap_or(false) =>
+            {
+                self.resume_http_request();
+            }
+            Some(rejection) => {
+                info!(
+                    "on_http_call_response: rejected {} (status {:?}): {}",
+                    call_token,
+                    status,
+                    rejection.reason()
+                );
+                if rejection != Rejection::BackendUnavailable {
+                    if let Some(app) = app {
+                        self.forget_authorized(app);
+                    }
+                }
+                self.reject(service, rejection);
+            }
         }
     }
 }
 
 struct RootAuthThreescale {
     vm_configuration: Option<Vec<u8>>,
-    configuration: Option<Configuration>,
+    // what to do with requests while no configuration is loaded
+    unconfigured: Unconfigured,
+    // configuration in use, ie. the plugin configuration along with that fetched from System,
+    // shared with the HTTP contexts created while it is
+    configuration: Option<Rc<Configuration>>,
+    // built along with the configuration in use
+    index: Rc<MatcherIndex>,
+    plugin_configuration: Option<Configuration>,
+    // shared queue of usage to be reported later
+    reports_queue: Option<u32>,
+    // most applications whose usage a flush reports, with that of the rest left queued
+    max_pending_apps: usize,
+    metrics: Metrics,
+    // usage being reported, by call token
+    reports_in_flight: HashMap<u32, Vec<Report>>,
+    // reports queued since the last flush, as notified to this VM
+    queued: usize,
+    // set once the host asks us to finish, so that we signal when reports are done
+    shutting_down: bool,
+    proxy_configs: ProxyConfigs,
 }
 
 impl RootAuthThreescale {
     pub fn new() -> Self {
         Self {
             vm_configuration: None,
+            unconfigured: Unconfigured::default(),
             configuration: None,
+            index: Rc::new(MatcherIndex::default()),
+            plugin_configuration: None,
+            reports_queue: None,
+            max_pending_apps: MAX_PENDING_APPS,
+            metrics: Metrics::default(),
+            reports_in_flight: HashMap::new(),
+            queued: 0,
+            shutting_down: false,
+            proxy_configs: ProxyConfigs::default(),
         }
     }
+
+    // Replaces the configuration used by HTTP contexts created from now on, while existing
+    // ones keep theirs.
+    fn set_configuration(&mut self, configuration: Configuration) {
+        self.index = Rc::new(MatcherIndex::new(&configuration));
+        self.configuration = Some(Rc::new(configuration));
+    }
+
+    // Asks System for the configuration of each service not being fetched already, to be
+    // applied as it arrives.
+    fn fetch_proxy_configs(&mut self, now: u64) {
+        let configuration = match self.plugin_configuration.as_ref() {
+            Some(configuration) => configuration,
+            None => return,
+        };
+        let (system, services) = match (configuration.system(), configuration.services()) {
+            (Some(system), Some(services)) => (system, services),
+            _ => return,
+        };
+        if !self.proxy_configs.fetch_due(now, system.refresh_interval()) {
+            return;
+        }
+        self.proxy_configs.fetched_at(now);
+
+        for service in services {
+            if self.proxy_configs.fetching(service.id()) {
+                continue;
+            }
+            let etag = self.proxy_configs.etag(service.id());
+            match system::fetch_proxy_config(self, system, service, etag) {
+                Ok(call_token) => {
+                    debug!(
+                        "fetching configuration of service {} from System, call token is {}",
+                        service.id(),
+                        call_token
+                    );
+                    self.proxy_configs.add_call(call_token, service.id());
+                }
+                Err(e) => warn!(
+                    "could not fetch configuration of service {} from System: {:#?}",
+                    service.id(),
+                    e
+                ),
+            }
+        }
+    }
+
+    // Applies a newer configuration of a service from System, keeping the last good one when
+    // it could not be fetched or parsed.
+    fn on_proxy_config_response(
+        &mut self,
+        service_id: &str,
+        status: Option<u32>,
+        etag: Option<String>,
+        body: Option<Bytes>,
+    ) {
+        let body = match (status, body) {
+            (Some(304), _) => {
+                debug!(
+                    "configuration of service {} unchanged in System",
+                    service_id
+                );
+                return;
+            }
+            (Some(200), Some(body)) => body,
+            _ => {
+                warn!(
+                    "could not fetch configuration of service {} from System (status {:?}), keeping the current one",
+                    service_id, status
+                );
+                return;
+            }
+        };
+        let proxy_config = match ProxyConfig::from_response(body.as_slice()) {
+            Ok(proxy_config) => proxy_config,
+            Err(e) => {
+                warn!(
+                    "could not parse configuration of service {} from System, keeping the current one: {}",
+                    service_id, e
+                );
+                return;
+            }
+        };
+        let plugin_configuration = match self.plugin_configuration.as_ref() {
+            Some(plugin_configuration) => plugin_configuration,
+            None => return,
+        };
+
+        let version = proxy_config.version();
+        let environment = proxy_config.environment().to_string();
+        if !self.proxy_configs.update(service_id, proxy_config, etag) {
+            debug!(
+                "configuration version {} of service {} from System is not newer, ignoring it",
+                version, service_id
+            );
+            return;
+        }
+
+        let configuration = self.proxy_configs.apply(plugin_configuration);
+        self.set_configuration(configuration);
+        info!(
+            "applied version {} of the {} configuration of service {} from System",
+            version, environment, service_id
+        );
+    }
+
+    fn on_report_response(&mut self, call_token: u32, status: Option<u32>, reports: Vec<Report>) {
+        match status {
+            Some(status) if status < 300 => {
+                debug!("on_http_call_response: report {} accepted", call_token);
+            }
+            Some(status) if status < 500 => {
+                // retrying would not help
+                error!(
+                    "on_http_call_response: report {} rejected with status {}, dropping usage",
+                    call_token, status
+                );
+            }
+            _ => {
+                warn!(
+                    "on_http_call_response: report {} failed (status {:?}), queueing usage again",
+                    call_token, status
+                );
+                self.requeue_usage(reports);
+            }
+        }
+    }
+
+    fn requeue_usage(&self, reports: Vec<Report>) {
+        for report in reports {
+            if !self.reports_queue.map_or(false, |reports_queue| {
+                shared::enqueue_report(reports_queue, &report)
+            }) {
+                warn!(
+                    "could not queue usage again, dropping usage of an application of service {}",
+                    report.0.service_id
+                );
+                self.metrics.increment(Counter::UsageDropped);
+            }
+        }
+    }
+
+    // Dequeues all the usage queued by any VM and reports it in calls of at most the
+    // configured batch size.
+    fn flush(&mut self) {
+        let reports_queue = match self.reports_queue {
+            Some(reports_queue) => reports_queue,
+            None => return,
+        };
+        let configuration = match self.configuration.as_ref() {
+            Some(configuration) => configuration,
+            None => return,
+        };
+        let backend = match configuration.backend() {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        // The queue is drained completely so that it does not grow without bounds: usage of
+        // applications over the cap is aggregated and queued again, and any left is dropped.
+        let mut pending = PendingUsage::new(self.max_pending_apps);
+        let mut deferred = PendingUsage::new(self.max_pending_apps);
+        while let Some((app, usages)) = shared::dequeue_report(reports_queue) {
+            if !pending.add(&app, &usages) && !deferred.add(&app, &usages) {
+                warn!(
+                    "flush: too many applications pending, dropping usage of an application of service {}",
+                    app.service_id
+                );
+                self.metrics.increment(Counter::UsageDropped);
+            }
+        }
+        self.queued = 0;
+
+        let mut pending = pending.take();
+        for (service_id, reports) in deferred.take() {
+            if self.shutting_down {
+                // there is no next flush to leave them for
+                pending.entry(service_id).or_default().extend(reports);
+            } else {
+                self.metrics.add(Counter::UsageDeferred, reports.len());
+                self.requeue_usage(reports);
+            }
+        }
+        let batch_size = backend.report_max_batch_size();
+
+        for (service_id, mut reports) in pending {
+            let service = match configuration.get_service(service_id.as_str()) {
+                Some(service) => service,
+                None => {
+                    warn!("flush: dropping usage of unknown service {}", service_id);
+                    continue;
+                }
+            };
+
+            while !reports.is_empty() {
+                let rest = reports.split_off(batch_size.min(reports.len()));
+                let batch = core::mem::replace(&mut reports, rest);
+                match self.report(backend, service, batch.as_slice()) {
+                    Ok(call_token) => {
+                        debug!(
+                            "flush: reporting usage of {} applications of service {}, call token is {}",
+                            batch.len(),
+                            service_id,
+                            call_token
+                        );
+                        self.reports_in_flight.insert(call_token, batch);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "flush: could not report usage of service {}: {:#?}",
+                            service_id, e
+                        );
+                        self.requeue_usage(batch);
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(
+        &self,
+        backend: &Backend,
+        service: &Service,
+        reports: &[Report],
+    ) -> Result<u32, anyhow::Error> {
+        let request = authrep::build_report_call(service, reports)?;
+        let (uri, body) = request.uri_and_body();
+        let headers = request
+            .headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+
+        backend.upstream().call(
+            self,
+            uri.as_ref(),
+            request.method.as_str(),
+            headers,
+            body.map(str::as_bytes),
+            None,
+            None,
+        )
+    }
 }
 
-impl Context for RootAuthThreescale {}
+impl Context for RootAuthThreescale {
+    fn on_http_call_response(&mut self, call_token: u32, _: usize, body_size: usize, _: usize) {
+        let headers = self.get_http_call_response_headers();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key.as_str() == name)
+                .map(|(_, value)| value)
+        };
+        let status = header(":status").and_then(|value| value.parse::<u32>().ok());
+
+        if let Some(service_id) = self.proxy_configs.take_call(call_token) {
+            let etag = header("etag").cloned();
+            let body = self.get_http_call_response_body(0, body_size);
+            self.on_proxy_config_response(service_id.as_str(), status, etag, body);
+        } else if let Some(reports) = self.reports_in_flight.remove(&call_token) {
+            self.on_report_response(call_token, status, reports);
+        } else {
+            warn!("on_http_call_response: unknown call token {}", call_token);
+            return;
+        }
+
+        if self.shutting_down && self.reports_in_flight.is_empty() {
+            info!("on_http_call_response: all pending usage reported");
+            self.done();
+        }
+    }
+
+    fn on_done(&mut self) -> bool {
+        info!("on_done: reporting pending usage");
+        self.shutting_down = true;
+        self.flush();
+
+        self.reports_in_flight.is_empty()
+    }
+}
 
 impl RootContext for RootAuthThreescale {
     fn on_vm_start(&mut self, vm_configuration_size: usize) -> bool {
@@ -186,12 +777,18 @@ impl RootContext for RootAuthThreescale {
         }
 
         self.vm_configuration = vm_config.unwrap();
+        self.metrics = Metrics::define();
 
         if let Some(conf) = self.vm_configuration.as_ref() {
             info!(
                 "on_vm_start: VM configuration is {}",
-                core::str::from_utf8(conf).unwrap()
+                String::from_utf8_lossy(conf)
             );
+            // VM configurations not meant for us are fine, the defaults apply then
+            match VmConfiguration::try_from(conf.as_slice()) {
+                Ok(vm_configuration) => self.unconfigured = vm_configuration.unconfigured(),
+                Err(e) => debug!("on_vm_start: using default VM settings: {}", e),
+            }
             true
         } else {
             warn!("on_vm_start: empty VM config");
@@ -200,8 +797,6 @@ impl RootContext for RootAuthThreescale {
     }
 
     fn on_configure(&mut self, plugin_configuration_size: usize) -> bool {
-        use core::convert::TryFrom;
-
         info!(
             "on_configure: plugin_configuration_size is {}",
             plugin_configuration_size
@@ -214,7 +809,13 @@ impl RootContext for RootAuthThreescale {
         ) {
             Ok(Some(conf)) => conf,
             Ok(None) => {
-                warn!("empty module configuration - module has no effect");
+                warn!(
+                    "empty module configuration - {} all requests",
+                    match self.unconfigured {
+                        Unconfigured::Allow => "allowing",
+                        Unconfigured::Deny => "denying",
+                    }
+                );
                 return true;
             }
             Err(e) => {
@@ -241,20 +842,97 @@ impl RootContext for RootAuthThreescale {
             }
         };
 
-        self.configuration = conf.into();
+        if let Err(errors) = conf.validate() {
+            error!("invalid configuration, {} errors found:", errors.len());
+            for e in errors {
+                error!("{}", e);
+            }
+            return false;
+        }
+
+        if conf.backend().is_some() || conf.system().is_some() {
+            self.set_tick_period(core::time::Duration::from_secs(TICK_PERIOD_SECS));
+        }
+        if conf.backend().is_some() {
+            self.reports_queue = shared::register_reports_queue();
+        }
+
+        // keep what was fetched from System so far, which is not fetched again if unchanged
+        self.set_configuration(self.proxy_configs.apply(&conf));
+        self.plugin_configuration = Some(conf);
         info!(
             "on_configure: plugin configuration {:#?}",
             self.configuration
         );
+        self.fetch_proxy_configs(current_time_secs(self));
 
         true
     }
 
+    fn on_tick(&mut self) {
+        let now = current_time_secs(self);
+        self.fetch_proxy_configs(now);
+
+        let backend = match self
+            .configuration
+            .as_deref()
+            .and_then(Configuration::backend)
+        {
+            Some(backend) => backend,
+            None => return,
+        };
+
+        if !shared::claim_flush(now, backend.report_flush_interval()) {
+            return;
+        }
+
+        if let Some(cached) = shared::cache_expire(now) {
+            debug!("on_tick: {} cached authorizations", cached);
+        }
+
+        self.flush();
+    }
+
+    fn on_queue_ready(&mut self, queue_id: u32) {
+        if Some(queue_id) != self.reports_queue {
+            return;
+        }
+
+        let batch_size = match self
+            .configuration
+            .as_deref()
+            .and_then(Configuration::backend)
+        {
+            Some(backend) => backend.report_max_batch_size(),
+            None => return,
+        };
+        self.queued += 1;
+        if self.queued >= batch_size {
+            self.flush();
+        }
+    }
+
     fn on_create_child_context(&mut self, context_id: u32) -> Option<ChildContext> {
         info!("threewscale_wasm_auth: creating new context {}", context_id);
+        let configuration = match self.configuration.as_ref() {
+            Some(configuration) => Rc::clone(configuration),
+            None => {
+                return Some(ChildContext::HttpContext(Box::new(
+                    UnconfiguredHttpAuthThreescale {
+                        context_id,
+                        unconfigured: self.unconfigured,
+                    },
+                )))
+            }
+        };
         let ctx = HttpAuthThreescale {
             context_id,
-            configuration: self.configuration.as_ref().unwrap().clone(),
+            configuration,
+            index: Rc::clone(&self.index),
+            reports_queue: self.reports_queue,
+            metrics: self.metrics,
+            app: None,
+            usages: HashMap::new(),
         };
 
         Some(ChildContext::HttpContext(Box::new(ctx)))
@@ -284,3 +962,647 @@ extern "C" fn start() {
         Box::new(RootAuthThreescale::new())
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use authrep::response::Period;
+    use test_backend::MockBackend;
+    use test_host::TestHost;
+
+    const CONFIGURATION: &str = r#"{
+        "backend": {
+          "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+        },
+        "services": [{
+          "id": "2555417834780",
+          "token": "service_token",
+          "authorities": ["web.app"],
+          "credentials": [
+            { "kind": "user_key", "keys": ["x-api-key"], "locations": [{ "location": "header" }] }
+          ],
+          "mapping_rules": [
+            { "method": "GET", "pattern": "/", "usages": [{ "name": "hits", "delta": 1 }] }
+          ]
+        }]
+      }"#;
+
+    const AUTHORIZED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>true</authorized>
+  <plan>Basic</plan>
+</status>"#;
+
+    const LIMITS_EXCEEDED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>false</authorized>
+  <reason>usage limits are exceeded</reason>
+  <plan>Basic</plan>
+</status>"#;
+
+    fn configured_root(host: &TestHost, configuration: &str) -> RootAuthThreescale {
+        let mut root = RootAuthThreescale::new();
+        host.set_vm_configuration("{}");
+        host.set_plugin_configuration(configuration);
+        assert!(root.on_vm_start(2));
+        assert!(root.on_configure(configuration.len()));
+        root
+    }
+
+    fn http_context(root: &mut RootAuthThreescale, context_id: u32) -> Box<dyn HttpContext> {
+        match root.on_create_child_context(context_id) {
+            Some(ChildContext::HttpContext(ctx)) => ctx,
+            _ => panic!("no HTTP context created"),
+        }
+    }
+
+    fn request(host: &TestHost, authority: &str, path: &str, api_key: &str) {
+        host.set_request_headers(&[
+            (":authority", authority),
+            (":method", "GET"),
+            (":path", path),
+            ("x-api-key", api_key),
+        ]);
+    }
+
+    #[test]
+    fn it_authorizes_requests_with_the_backend() {
+        let host = TestHost::new();
+        let mut root = configured_root(&host, CONFIGURATION);
+        assert_eq!(host.tick_period_ms(), 1000);
+        let mut ctx = http_context(&mut root, 2);
+
+        request(&host, "web.app", "/books", "secret");
+        assert_eq!(
+            ctx.on_http_request_headers(5),
+            FilterHeadersStatus::StopIteration
+        );
+        let calls = host.take_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].upstream, "backend");
+        assert_eq!(calls[0].header(":authority"), Some("su1.3scale.net"));
+        assert!(calls[0]
+            .header(":path")
+            .unwrap()
+            .starts_with("/transactions/authrep.xml"));
+        assert_eq!(calls[0].timeout_ms, 5000);
+
+        host.respond(&mut *ctx, calls[0].token, 200, &[], AUTHORIZED.as_bytes());
+        assert!(host.resumed());
+        assert_eq!(host.local_response(), None);
+
+        assert_eq!(
+            ctx.on_http_response_headers(0),
+            FilterHeadersStatus::Continue
+        );
+        assert_eq!(
+            host.response_headers(),
+            vec![("Powered-By".to_string(), "3scale".to_string())]
+        );
+    }
+
+    #[test]
+    fn it_rejects_requests_the_backend_does_not_authorize() {
+        let host = TestHost::new();
+        let mut root = configured_root(&host, CONFIGURATION);
+        let mut ctx = http_context(&mut root, 2);
+
+        request(&host, "web.app", "/books", "secret");
+        ctx.on_http_request_headers(5);
+        let calls = host.take_calls();
+        host.respond(
+            &mut *ctx,
+            calls[0].token,
+            409,
+            &[],
+            LIMITS_EXCEEDED.as_bytes(),
+        );
+
+        assert!(!host.resumed());
+        let response = host.local_response().unwrap();
+        assert_eq!(response.status, 429);
+        assert_eq!(response.body, b"Too many requests.\n".to_vec());
+    }
+
+    #[test]
+    fn it_rejects_requests_locally() {
+        let host = TestHost::new();
+        let mut root = configured_root(&host, CONFIGURATION);
+
+        let mut ctx = http_context(&mut root, 2);
+        request(&host, "unknown.app", "/books", "secret");
+        assert_eq!(
+            ctx.on_http_request_headers(5),
+            FilterHeadersStatus::StopIteration
+        );
+        assert_eq!(host.local_response().unwrap().status, 403);
+
+        let mut ctx = http_context(&mut root, 3);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/"),
+        ]);
+        assert_eq!(
+            ctx.on_http_request_headers(3),
+            FilterHeadersStatus::StopIteration
+        );
+        assert_eq!(host.local_response().unwrap().status, 401);
+        assert!(host.take_calls().is_empty());
+    }
+
+    #[test]
+    fn it_denies_requests_until_configured() {
+        let host = TestHost::new();
+        let mut root = RootAuthThreescale::new();
+        host.set_vm_configuration("{}");
+        assert!(root.on_vm_start(2));
+        // no plugin configuration
+        assert!(root.on_configure(0));
+
+        let mut ctx = http_context(&mut root, 2);
+        request(&host, "web.app", "/books", "secret");
+        assert_eq!(
+            ctx.on_http_request_headers(5),
+            FilterHeadersStatus::StopIteration
+        );
+        assert_eq!(host.local_response().unwrap().status, 503);
+    }
+
+    fn mock_backend(hits_per_day: u64) -> MockBackend {
+        let mut backend = MockBackend::new();
+        backend
+            .add_service("2555417834780", "service_token")
+            .add_user_key("2555417834780", "secret")
+            .add_limit("2555417834780", "secret", "hits", Period::Day, hits_per_day);
+        backend
+    }
+
+    // Queues hits of an application identified by its user key, as an HTTP context would.
+    fn queue_hits(root: &RootAuthThreescale, user_key: &str, hits: i64) {
+        let app = AppRef {
+            service_id: "2555417834780".to_string(),
+            kind: crate::configuration::ApplicationKind::UserKey,
+            app_id: user_key.to_string(),
+            app_key: None,
+        };
+        let usages = std::iter::once(("hits".to_string(), hits)).collect();
+        assert!(shared::enqueue_report(
+            root.reports_queue.unwrap(),
+            &(app, usages)
+        ));
+    }
+
+    #[test]
+    fn it_enforces_backend_limits() {
+        let host = TestHost::new();
+        let mut backend = mock_backend(1);
+        let mut root = configured_root(&host, CONFIGURATION);
+
+        let mut ctx = http_context(&mut root, 2);
+        request(&host, "web.app", "/books", "secret");
+        ctx.on_http_request_headers(4);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(host.resumed());
+
+        let mut ctx = http_context(&mut root, 3);
+        request(&host, "web.app", "/books", "secret");
+        ctx.on_http_request_headers(4);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(!host.resumed());
+        let response = host.local_response().unwrap();
+        assert_eq!(response.status, 429);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("retry-after")));
+
+        let mut ctx = http_context(&mut root, 4);
+        request(&host, "web.app", "/books", "wrong");
+        ctx.on_http_request_headers(4);
+        backend.serve(&host, &mut *ctx);
+        assert_eq!(host.local_response().unwrap().status, 403);
+
+        assert_eq!(backend.usage("2555417834780", "secret", "hits"), 1);
+    }
+
+    #[test]
+    fn it_reports_usage_authorized_from_cache() {
+        let host = TestHost::new();
+        let mut backend = mock_backend(10);
+        let configuration = CONFIGURATION.replace(
+            r#""timeout": 5000 }"#,
+            r#""timeout": 5000 }, "cache": { "ttl": 60 }, "reporting": { "flush_interval": 10 }"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        let mut ctx = http_context(&mut root, 2);
+        request(&host, "web.app", "/books", "secret");
+        ctx.on_http_request_headers(4);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(host.resumed());
+
+        let mut ctx = http_context(&mut root, 3);
+        request(&host, "web.app", "/books", "secret");
+        assert_eq!(
+            ctx.on_http_request_headers(4),
+            FilterHeadersStatus::Continue
+        );
+        assert!(host.take_calls().is_empty());
+        assert_eq!(backend.usage("2555417834780", "secret", "hits"), 1);
+
+        host.advance_time(10);
+        root.on_tick();
+        assert_eq!(backend.serve(&host, &mut root), 1);
+        assert_eq!(backend.usage("2555417834780", "secret", "hits"), 2);
+    }
+
+    #[test]
+    fn it_reports_pending_usage_in_batches() {
+        let host = TestHost::new();
+        let mut backend = MockBackend::new();
+        backend.add_service("2555417834780", "service_token");
+        let user_keys = ["a", "b", "c", "d", "e"];
+        for user_key in user_keys.iter() {
+            backend.add_user_key("2555417834780", user_key);
+        }
+        let configuration = CONFIGURATION.replace(
+            r#""timeout": 5000 }"#,
+            r#""timeout": 5000 }, "reporting": { "max_batch_size": 2 }"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        for user_key in user_keys.iter() {
+            queue_hits(&root, user_key, 1);
+        }
+        queue_hits(&root, "a", 2);
+
+        host.advance_time(10);
+        root.on_tick();
+        assert_eq!(backend.serve(&host, &mut root), 3);
+        assert_eq!(backend.usage("2555417834780", "a", "hits"), 3);
+        for user_key in user_keys.iter().skip(1) {
+            assert_eq!(backend.usage("2555417834780", user_key, "hits"), 1);
+        }
+    }
+
+    #[test]
+    fn it_leaves_usage_over_the_pending_cap_for_the_next_flush() {
+        let host = TestHost::new();
+        let mut backend = MockBackend::new();
+        backend.add_service("2555417834780", "service_token");
+        let user_keys = ["a", "b", "c", "d", "e"];
+        for user_key in user_keys.iter() {
+            backend.add_user_key("2555417834780", user_key);
+        }
+        let mut root = configured_root(&host, CONFIGURATION);
+        root.max_pending_apps = 2;
+
+        for user_key in user_keys.iter() {
+            queue_hits(&root, user_key, 1);
+        }
+        queue_hits(&root, "c", 1);
+
+        host.advance_time(10);
+        root.on_tick();
+        assert_eq!(backend.serve(&host, &mut root), 1);
+        assert_eq!(backend.usage("2555417834780", "a", "hits"), 1);
+        assert_eq!(backend.usage("2555417834780", "b", "hits"), 1);
+        assert_eq!(backend.usage("2555417834780", "c", "hits"), 0);
+        assert_eq!(host.metric("threescale_wasm_auth.usage.deferred"), Some(2));
+        assert_eq!(host.metric("threescale_wasm_auth.usage.dropped"), Some(1));
+
+        host.advance_time(10);
+        root.on_tick();
+        assert_eq!(backend.serve(&host, &mut root), 1);
+        assert_eq!(backend.usage("2555417834780", "c", "hits"), 2);
+        assert_eq!(backend.usage("2555417834780", "d", "hits"), 1);
+        assert_eq!(backend.usage("2555417834780", "e", "hits"), 0);
+
+        host.advance_time(10);
+        root.on_tick();
+        assert!(host.take_calls().is_empty());
+    }
+
+    #[test]
+    fn it_reports_all_pending_usage_when_done() {
+        let host = TestHost::new();
+        let mut backend = MockBackend::new();
+        backend.add_service("2555417834780", "service_token");
+        let user_keys = ["a", "b", "c", "d"];
+        for user_key in user_keys.iter() {
+            backend.add_user_key("2555417834780", user_key);
+        }
+        let configuration = CONFIGURATION.replace(
+            r#""timeout": 5000 }"#,
+            r#""timeout": 5000 }, "reporting": { "max_batch_size": 3 }"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+        root.max_pending_apps = 2;
+
+        for user_key in user_keys.iter() {
+            queue_hits(&root, user_key, 1);
+        }
+
+        assert!(!root.on_done());
+        assert!(!host.done());
+        assert_eq!(backend.serve(&host, &mut root), 2);
+        assert!(host.done());
+        for user_key in user_keys.iter() {
+            assert_eq!(backend.usage("2555417834780", user_key, "hits"), 1);
+        }
+        assert_eq!(host.metric("threescale_wasm_auth.usage.deferred"), Some(0));
+    }
+
+    #[test]
+    fn it_keeps_configurations_from_system_when_reconfigured() {
+        let host = TestHost::new();
+        let configuration = CONFIGURATION
+            .replace(
+                r#""backend": {"#,
+                r#""system": {
+          "upstream": { "name": "system", "url": "https://system", "timeout": 5000 },
+          "token": "system_token"
+        },
+        "backend": {"#,
+            )
+            .replace(
+                r#"{ "method": "GET", "pattern": "/", "usages": [{ "name": "hits", "delta": 1 }] }"#,
+                "",
+            );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        let calls = host.take_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].upstream, "system");
+        let proxy_config = r#"{ "proxy_config": { "version": 1, "environment": "production", "content": {
+            "backend_version": "1",
+            "proxy": { "proxy_rules": [
+              { "http_method": "GET", "pattern": "/books", "metric_system_name": "hits", "delta": 1 }
+            ] }
+        } } }"#;
+        host.respond(
+            &mut root,
+            calls[0].token,
+            200,
+            &[("etag", "\"1\"")],
+            proxy_config.as_bytes(),
+        );
+
+        for context_id in 2..4 {
+            let mut ctx = http_context(&mut root, context_id);
+            request(&host, "web.app", "/books", "secret");
+            assert_eq!(
+                ctx.on_http_request_headers(4),
+                FilterHeadersStatus::StopIteration
+            );
+            assert_eq!(host.local_response(), None);
+            assert_eq!(host.take_calls()[0].upstream, "backend");
+
+            // the same plugin configuration again, with nothing new from System
+            assert!(root.on_configure(configuration.len()));
+            assert!(host.take_calls().is_empty());
+        }
+    }
+
+    #[test]
+    fn it_finds_credentials_in_properties() {
+        use prost::Message;
+
+        let host = TestHost::new();
+        let mut backend = MockBackend::new();
+        backend
+            .add_service("2555417834780", "service_token")
+            .add_app_id("2555417834780", "my-client", &[]);
+        let configuration = CONFIGURATION.replace(
+            r#"{ "kind": "user_key", "keys": ["x-api-key"], "locations": [{ "location": "header" }] }"#,
+            r#"{ "kind": "oidc", "keys": ["/envoy.filters.http.jwt_authn/verified_jwt/azp"],
+                 "locations": [{ "location": "property", "path": ["metadata"], "decode": ["protobuf"] }] }"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        let string = |s: &str| prost_types::Value {
+            kind: Some(prost_types::value::Kind::StringValue(s.to_string())),
+        };
+        let verified_jwt = prost_types::Struct {
+            fields: vec![
+                ("azp".to_string(), string("my-client")),
+                ("aud".to_string(), string("web")),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let jwt_authn = prost_types::Struct {
+            fields: std::iter::once((
+                "verified_jwt".to_string(),
+                prost_types::Value {
+                    kind: Some(prost_types::value::Kind::StructValue(verified_jwt)),
+                },
+            ))
+            .collect(),
+        };
+        let metadata = decode::Metadata {
+            filter_metadata: std::iter::once((
+                "envoy.filters.http.jwt_authn".to_string(),
+                jwt_authn,
+            ))
+            .collect(),
+        };
+        let mut property = Vec::new();
+        metadata.encode(&mut property).unwrap();
+        host.set_property(&["metadata"], property.as_slice());
+
+        let mut ctx = http_context(&mut root, 2);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+        ]);
+        ctx.on_http_request_headers(3);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(host.resumed());
+        assert_eq!(backend.usage("2555417834780", "my-client", "hits"), 1);
+    }
+
+    #[test]
+    fn it_finds_credentials_in_cookies() {
+        let host = TestHost::new();
+        let mut backend = mock_backend(10);
+        let configuration = CONFIGURATION.replace(
+            r#""locations": [{ "location": "header" }]"#,
+            r#""locations": [{ "location": "cookie", "decode": ["base64urldec"] }]"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        let mut ctx = http_context(&mut root, 2);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+            ("cookie", "theme=dark; x-api-key=c2VjcmV0"),
+        ]);
+        ctx.on_http_request_headers(4);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(host.resumed());
+        assert_eq!(backend.usage("2555417834780", "secret", "hits"), 1);
+
+        // the header location is not looked into anymore
+        let mut ctx = http_context(&mut root, 3);
+        request(&host, "web.app", "/books", "secret");
+        ctx.on_http_request_headers(4);
+        assert!(host.take_calls().is_empty());
+        assert_eq!(host.local_response().unwrap().status, 401);
+    }
+
+    fn app_id_configuration() -> String {
+        CONFIGURATION.replace(
+            r#"{ "kind": "user_key", "keys": ["x-api-key"], "locations": [{ "location": "header" }] }"#,
+            r#"{ "kind": "app_id", "keys": ["x-app-id"], "locations": [{ "location": "header" }] },
+               { "kind": "app_key", "keys": ["x-app-key"], "locations": [{ "location": "header" }] }"#,
+        )
+    }
+
+    #[test]
+    fn it_authorizes_app_ids_with_their_app_keys() {
+        let host = TestHost::new();
+        let mut backend = MockBackend::new();
+        backend
+            .add_service("2555417834780", "service_token")
+            .add_app_id("2555417834780", "my-app", &["my-key"]);
+        let mut root = configured_root(&host, app_id_configuration().as_str());
+
+        let mut ctx = http_context(&mut root, 2);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+            ("x-app-id", "my-app"),
+            ("x-app-key", "my-key"),
+        ]);
+        ctx.on_http_request_headers(5);
+        let calls = host.take_calls();
+        let path = calls[0].header(":path").unwrap();
+        let query = &path[path.find('?').unwrap() + 1..];
+        assert!(url::form_urlencoded::parse(query.as_bytes())
+            .any(|(param, value)| param == "app_key" && value == "my-key"));
+        let (status, body) = backend.handle(&calls[0], host.now());
+        host.respond(&mut *ctx, calls[0].token, status, &[], body.as_bytes());
+        assert!(host.resumed());
+        assert_eq!(backend.usage("2555417834780", "my-app", "hits"), 1);
+
+        let mut ctx = http_context(&mut root, 3);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+            ("x-app-id", "my-app"),
+            ("x-app-key", "wrong-key"),
+        ]);
+        ctx.on_http_request_headers(5);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(!host.resumed());
+        assert_eq!(host.local_response().unwrap().status, 403);
+        assert_eq!(backend.usage("2555417834780", "my-app", "hits"), 1);
+    }
+
+    #[test]
+    fn it_rejects_app_ids_without_their_app_keys() {
+        let host = TestHost::new();
+        let mut root = configured_root(&host, app_id_configuration().as_str());
+
+        let mut ctx = http_context(&mut root, 2);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+            ("x-app-id", "my-app"),
+        ]);
+        assert_eq!(
+            ctx.on_http_request_headers(4),
+            FilterHeadersStatus::StopIteration
+        );
+        assert!(host.take_calls().is_empty());
+        let response = host.local_response().unwrap();
+        assert_eq!(response.status, 401);
+        assert_eq!(response.body, b"Authentication required.\n".to_vec());
+    }
+
+    #[test]
+    fn it_rejects_missing_credentials_as_the_service_configures() {
+        let host = TestHost::new();
+        let configuration = CONFIGURATION.replace(
+            r#""authorities": ["web.app"],"#,
+            r#""authorities": ["web.app"],
+          "responses": {
+            "credentials_missing": { "status": 401, "headers": [["www-authenticate", "X-Api-Key"]],
+                                     "body": "no key: {reason}" }
+          },"#,
+        );
+        let mut root = configured_root(&host, configuration.as_str());
+
+        let mut ctx = http_context(&mut root, 2);
+        host.set_request_headers(&[
+            (":authority", "web.app"),
+            (":method", "GET"),
+            (":path", "/books"),
+        ]);
+        ctx.on_http_request_headers(3);
+        let response = host.local_response().unwrap();
+        assert_eq!(response.status, 401);
+        assert!(response
+            .headers
+            .contains(&("www-authenticate".to_string(), "X-Api-Key".to_string())));
+        assert_eq!(
+            response.body,
+            b"no key: no credentials found in request".to_vec()
+        );
+    }
+
+    #[test]
+    fn it_counts_requests_matching_no_mapping_rule() {
+        let host = TestHost::new();
+        let mut backend = mock_backend(10);
+        let unmatched = |policy: &str| {
+            CONFIGURATION
+                .replace(r#""pattern": "/","#, r#""pattern": "/books","#)
+                .replace(
+                    r#""authorities": ["web.app"],"#,
+                    &format!(r#""authorities": ["web.app"], "unmatched": {},"#, policy),
+                )
+        };
+
+        let mut root = configured_root(&host, unmatched(r#""deny""#).as_str());
+        let mut ctx = http_context(&mut root, 2);
+        request(&host, "web.app", "/other", "secret");
+        ctx.on_http_request_headers(4);
+        assert_eq!(host.local_response().unwrap().status, 404);
+
+        let mut root = configured_root(&host, unmatched(r#""allow""#).as_str());
+        let mut ctx = http_context(&mut root, 3);
+        request(&host, "web.app", "/other", "secret");
+        assert_eq!(
+            ctx.on_http_request_headers(4),
+            FilterHeadersStatus::Continue
+        );
+        assert!(host.take_calls().is_empty());
+
+        let policy = r#"{ "report": { "name": "hits", "delta": 2 } }"#;
+        let mut root = configured_root(&host, unmatched(policy).as_str());
+        let mut ctx = http_context(&mut root, 4);
+        request(&host, "web.app", "/other", "secret");
+        ctx.on_http_request_headers(4);
+        assert_eq!(backend.serve(&host, &mut *ctx), 1);
+        assert!(host.resumed());
+        assert_eq!(backend.usage("2555417834780", "secret", "hits"), 2);
+
+        for counter in [
+            Counter::UnmatchedDenied,
+            Counter::UnmatchedAllowed,
+            Counter::UnmatchedReported,
+        ]
+        .iter()
+        {
+            assert_eq!(host.metric(counter.name()), Some(1), "{}", counter.name());
+        }
+    }
+}
@@ -3,10 +3,13 @@
 
 use std::vec;
 
+pub(crate) mod response;
+
 use super::decode::Value;
 use super::request_headers::RequestHeaders;
+use super::state::{AppRef, Report};
 use super::HttpAuthThreescale;
-use crate::configuration::{ApplicationKind, Decode, Format, Location};
+use crate::configuration::{ApplicationKind, Decode, Format, Location, MatcherIndex, Parameter};
 use log::{debug, warn};
 use protobuf::{well_known_types, Message};
 use proxy_wasm::traits::Context;
@@ -23,11 +26,16 @@ use threescalers::{
 };
 
 #[derive(Debug, Error)]
-enum MatchError {
+pub(crate) enum MatchError {
     #[error("no known service matched")]
     NoServiceMatched,
-    #[error("no credentials found in request")]
-    CredentialsNotFound,
+    // the id of the service matched is kept to reject the request as it configures
+    #[error("no credentials found in request for service {0}")]
+    CredentialsNotFound(String),
+    #[error("no app key found in request for the app id of service {0}")]
+    AppKeyNotFound(String),
+    #[error("no mapping rule matched")]
+    NoMappingRuleMatched,
 }
 
 #[derive(Debug, Error)]
@@ -40,8 +48,9 @@ pub(crate) fn authrep_request(
     ctx: &HttpAuthThreescale,
     rh: &RequestHeaders,
 ) -> Result<Request, anyhow::Error> {
-    let (svc, kind, app_id, format, usages) = authrep(ctx, rh)?;
-    build_call(svc, kind, app_id, format, usages)
+    let (svc, app, format, usages) = authrep(ctx, rh)?;
+    let usages = usages.ok_or(MatchError::NoMappingRuleMatched)?;
+    build_call(svc, &app, format, usages)
 }
 pub(crate) fn authrep<'a>(
     ctx: &'a HttpAuthThreescale,
@@ -50,15 +59,16 @@ pub(crate) fn authrep<'a>(
 ) -> Result<
     (
         &'a crate::configuration::Service,
-        ApplicationKind,
-        String,
+        AppRef,
         Option<Format>,
-        std::collections::HashMap<&'a str, i64>,
+        // `None` when no mapping rule matched
+        Option<std::collections::HashMap<&'a str, i64>>,
     ),
     anyhow::Error,
 > {
     let config = ctx.configuration();
     let svclist = config.get_services()?;
+    let index = ctx.index();
 
     let metadata = rh.metadata();
     let method = metadata.method();
@@ -66,211 +76,223 @@ pub(crate) fn authrep<'a>(
     let authority = url.authority();
     let path = url.path();
 
-    let svc = svclist
-        .iter()
-        .find(|&svc| svc.match_authority(authority))
+    let (svc_idx, svc) = select_service(index, svclist, authority, method, path, rh)
         .ok_or(MatchError::NoServiceMatched)?;
 
     let credentials = svc.credentials()?;
 
-    let ((value, format), kind) = credentials
-        .iter()
-        .find_map(|param| {
-            let kind = param.kind();
-            let keys = param.keys();
-            param
-                .locations()
-                .iter()
-                .find_map(|location_info| -> Option<(Value, Option<Format>)> {
-                    let (decode, format) = {
-                        let dnf = location_info.value_dnf();
-                        (dnf.decode(), dnf.format())
-                    };
+    let find_value = |param: &Parameter<String>| {
+        let keys = param.keys();
+        param
+            .locations()
+            .iter()
+            .find_map(|location_info| -> Option<(Value, Option<Format>)> {
+                let (decode, format) = {
+                    let dnf = location_info.value_dnf();
+                    (dnf.decode(), dnf.format())
+                };
 
-                    match location_info.location() {
-                        Location::QueryString => keys.iter().find_map(|key| {
-                            url.query_pairs().find_map(|(k, v)| {
-                                if key == k.as_ref() {
-                                    match Value::String(v).decode_multiple(decode) {
-                                        Ok(v) => Ok(v),
-                                        Err(e) => {
-                                            warn!("Error decoding query_string {:#?}", e);
-                                            Err(e)
-                                        }
-                                    }
-                                    .ok()
-                                    .map(|v| (v, format))
-                                } else {
-                                    None
-                                }
-                            })
-                        }),
-                        Location::Header => keys
-                            .iter()
-                            .find_map(|key| rh.get(key))
-                            .map(std::borrow::Cow::from)
-                            .map(|v| {
+                match location_info.location() {
+                    Location::QueryString => keys.iter().find_map(|key| {
+                        url.query_pairs().find_map(|(k, v)| {
+                            if key == k.as_ref() {
                                 match Value::String(v).decode_multiple(decode) {
                                     Ok(v) => Ok(v),
                                     Err(e) => {
-                                        warn!("Error decoding header {:#?}", e);
+                                        warn!("Error decoding query_string: {}", e);
                                         Err(e)
                                     }
                                 }
                                 .ok()
                                 .map(|v| (v, format))
-                            })
-                            .flatten(),
-                        Location::Property => {
-                            // parse an explicit metadata path to look for the claims
-                            //let path = param
-                            //    .metadata()
-                            //    .and_then(|metadata| {
-                            //        metadata.get("path").and_then(|path| match path.as_str() {
-                            //            Some(s) => Some(s.split('/').collect::<Vec<&str>>()),
-                            //            None => path
-                            //                .as_array()?
-                            //                .iter()
-                            //                .map(serde_json::Value::as_str)
-                            //                .collect::<Option<_>>(),
-                            //        })
-                            //    })
-                            //    .unwrap_or_else(|| {
-                            //        vec![
-                            //            "metadata",
-                            //            "filter_metadata",
-                            //            "envoy.filters.http.jwt_authn",
-                            //            //"verified_jwt",
-                            //        ]
-                            //    });
-                            let _path = location_info
-                                .path()
-                                .map(|pc| pc.iter().map(|ps| ps.as_str()).collect::<Vec<_>>())
-                                .unwrap_or_else(|| {
-                                    if kind == ApplicationKind::OIDC {
-                                        vec![
-                                            "metadata",
-                                            //"filter_metadata",
-                                            //"envoy.filters.http.jwt_authn",
-                                            //"verified_jwt",
-                                        ]
-                                    } else {
-                                        vec![]
-                                    }
-                                });
-                            let paths_to_try = [
-                                vec!["metadata"],
-                                vec!["metadata", "filter_metadata"],
-                                vec![
-                                    "metadata",
-                                    "filter_metadata",
-                                    "envoy.filters.http.jwt_authn",
-                                ],
-                                vec![
-                                    "metadata",
-                                    "filter_metadata",
-                                    "envoy.filters.http.jwt_authn",
-                                    "verified_jwt",
-                                ],
-                                vec![
-                                    "metadata",
-                                    "filter_metadata",
-                                    "envoy.filters.http.jwt_authn",
-                                    "verified_jwt",
-                                    "azp",
-                                ],
-                            ];
-                            for path in paths_to_try.iter() {
-                                let path_s = path.join("/");
-                                debug!("Looking up property path {}", path_s);
-                                let _res = if let Some(property) = ctx.get_property(path.clone()) {
-                                    //let s = String::from_utf8_lossy(property.as_slice());
-                                    //debug!(
-                                    //    "Property value {} (len {}) =>\n{}",
-                                    //    path_s,
-                                    //    s.len(),
-                                    //    s.as_ref()
-                                    //);
+                            } else {
+                                None
+                            }
+                        })
+                    }),
+                    Location::Header => keys
+                        .iter()
+                        .find_map(|key| rh.get(key))
+                        .map(std::borrow::Cow::from)
+                        .map(|v| {
+                            match Value::String(v).decode_multiple(decode) {
+                                Ok(v) => Ok(v),
+                                Err(e) => {
+                                    warn!("Error decoding header: {}", e);
+                                    Err(e)
+                                }
+                            }
+                            .ok()
+                            .map(|v| (v, format))
+                        })
+                        .flatten(),
+                    Location::Cookie => keys
+                        .iter()
+                        .find_map(|key| rh.get_cookie_from_header("cookie", key).flatten())
+                        .map(std::borrow::Cow::from)
+                        .and_then(|v| match Value::String(v).decode_multiple(decode) {
+                            Ok(v) => Some((v, format)),
+                            Err(e) => {
+                                warn!("Error decoding cookie: {}", e);
+                                None
+                            }
+                        }),
+                    Location::Property => {
+                        let path = location_info
+                            .path()?
+                            .iter()
+                            .map(String::as_str)
+                            .collect::<Vec<_>>();
+                        let property = match ctx.get_property(path.clone()) {
+                            Some(property) => property,
+                            None => {
+                                debug!("property {} not found", path.join("/"));
+                                return None;
+                            }
+                        };
+                        let value = match Value::Bytes(std::borrow::Cow::from(property.as_slice()))
+                            .decode_multiple(decode)
+                        {
+                            Ok(value) => value,
+                            Err(e) => {
+                                warn!("Error decoding property {}: {}", path.join("/"), e);
+                                return None;
+                            }
+                        };
+                        keys.iter()
+                            .find_map(|key| value.lookup(key))
+                            .map(|v| (v, format))
+                    }
+                }
+            })
+    };
 
-                                    //let mut cis =
-                                    //    protobuf::CodedInputStream::from_bytes(property.as_slice());
-                                    //let mut st = protobuf::well_known_types::Struct::new();
-                                    //match st.merge_from(&mut cis) {
-                                    //    Ok(_) => debug!("merged OK"),
-                                    //    Err(e) => debug!("merge FAILED: {:#?}", e),
-                                    //}
+    let ((value, format), kind) = credentials
+        .iter()
+        .filter(|param| param.kind() != ApplicationKind::AppKey)
+        .find_map(|param| find_value(param).map(|value| (value, param.kind())))
+        .ok_or_else(|| MatchError::CredentialsNotFound(svc.id().to_string()))?;
 
-                                    // find first byte that matches & 0x0f < 6 for protobuf type 0-5
-                                    let b = property.as_slice();
-                                    let ss = b
-                                        .iter()
-                                        //    //.skip(113)
-                                        //    //.skip_while(|&&b| b & 0x0f > 5 || b == 0)
-                                        .map(|&b| b)
-                                        .collect::<Vec<_>>();
-                                    //let s = String::from_utf8_lossy(ss.as_slice());
-                                    //debug!("New Value (len {}) =>\n{}", s.len(), s.as_ref());
+    debug!("Found credentials, kind {:#?} format {:?}", kind, format);
+    // credentials have to be strings, as JSON numbers or objects are not
+    let value = value
+        .to_string()
+        .ok_or_else(|| MatchError::CredentialsNotFound(svc.id().to_string()))?;
 
-                                    match Value::Bytes(std::borrow::Cow::from(ss))
-                                        .decode_multiple(decode)
-                                    {
-                                        Ok(v) => Ok(v),
-                                        Err(e) => {
-                                            //warn!("Error decoding property {:#?}", e);
-                                            warn!("Error decoding property for {}", path_s);
-                                            Err(e)
-                                        }
-                                    }
-                                    .ok()
-                                    .map(|v| (v, format))
-                                } else {
-                                    debug!("Property path not found {}", path_s);
-                                    None
-                                };
-                            }
-                            None
-                        }
-                    }
-                })
-                .map(|value| (value, kind))
-        })
-        .ok_or(MatchError::CredentialsNotFound)?;
+    // app ids come with a key when the service declares where to find it
+    let mut app_key_params = credentials
+        .iter()
+        .filter(|param| param.kind() == ApplicationKind::AppKey)
+        .peekable();
+    let app_key = if kind == ApplicationKind::AppId && app_key_params.peek().is_some() {
+        let app_key = app_key_params
+            .find_map(find_value)
+            .and_then(|(app_key, _)| app_key.to_string())
+            .ok_or_else(|| MatchError::AppKeyNotFound(svc.id().to_string()))?;
+        Some(app_key)
+    } else {
+        None
+    };
 
-    debug!(
-        "Found credentials, kind {:#?} format {:?} value {:#?}",
-        kind, format, value
-    );
-    // XXX unwrap can panic here
-    let value = value.to_string().unwrap();
+    let app = AppRef {
+        service_id: svc.id().to_string(),
+        kind,
+        app_id: value,
+        app_key,
+    };
+
+    let rules = index.matching_rules(svc_idx, svc, method, path, url.query());
+    if rules.is_empty() {
+        return Ok((svc, app, format, None));
+    }
 
     let mut usages = std::collections::HashMap::new();
-    for rule in svc.mapping_rules() {
-        debug!("matching rule {:#?}", rule);
-        if method == rule.method().to_ascii_uppercase().as_str() && rule.match_pattern(path) {
-            debug!("matched pattern in {}", path);
-            for usage in rule.usages() {
-                let value = usages.entry(usage.name()).or_insert(0);
-                *value += usage.delta();
+    for rule in rules {
+        debug!("matched rule {:#?} in {}", rule, path);
+        for usage in rule.usages() {
+            let value = usages.entry(usage.name()).or_insert(0);
+            *value += usage.delta();
+        }
+    }
+
+    Ok((svc, app, format, Some(usages)))
+}
+
+// Picks the service matching the request most specifically, and the first one declared among
+// equally specific ones, along with its position.
+fn select_service<'a>(
+    index: &MatcherIndex,
+    services: &'a [crate::configuration::Service],
+    authority: &str,
+    method: &str,
+    path: &str,
+    rh: &RequestHeaders,
+) -> Option<(usize, &'a crate::configuration::Service)> {
+    // header names come in lowercase from the host
+    let header = |name: &str| rh.get(name.to_ascii_lowercase().as_str());
+    let mut best = None;
+    let mut tied = Vec::new();
+    for (idx, authority_match) in index.authority_matches(authority) {
+        let svc = &services[idx];
+        let m = match svc.match_selector(authority_match, method, path, header) {
+            Some(m) => m,
+            None => continue,
+        };
+        match best {
+            Some((best_m, _, _)) if best_m > m => (),
+            Some((best_m, _, _)) if best_m == m => tied.push(svc.id()),
+            _ => {
+                best = Some((m, idx, svc));
+                tied.clear();
             }
         }
     }
 
-    Ok((svc, kind, value, format, usages))
+    let (_, idx, svc) = best?;
+    if !tied.is_empty() {
+        warn!(
+            "request for {}{} matched service {} as well as {}, using the former",
+            authority,
+            path,
+            svc.id(),
+            tied.join(", ")
+        );
+    }
+
+    Some((idx, svc))
 }
 
-pub(crate) fn build_call(
-    service: &crate::configuration::Service,
+fn application(
     kind: ApplicationKind,
-    app_id: String,
-    _format: Option<Format>,
-    usages: std::collections::HashMap<&str, i64>,
-) -> Result<Request, anyhow::Error> {
+    app_id: &str,
+    app_key: Option<&str>,
+) -> Result<Application, anyhow::Error> {
     let app = match kind {
         ApplicationKind::UserKey => Application::UserKey(app_id.into()),
-        ApplicationKind::AppId | ApplicationKind::OIDC => Application::AppId(app_id.into(), None),
+        ApplicationKind::AppId | ApplicationKind::OIDC => {
+            Application::AppId(app_id.into(), app_key.map(Into::into))
+        }
         k => anyhow::bail!(UnimplementedError::CredentialsKind(k)),
     };
 
+    Ok(app)
+}
+
+fn threescale_service(service: &crate::configuration::Service) -> Service {
+    Service::new(
+        service.id(),
+        Credentials::ServiceToken(service.token().into()),
+    )
+}
+
+pub(crate) fn build_call(
+    service: &crate::configuration::Service,
+    app: &AppRef,
+    _format: Option<Format>,
+    usages: std::collections::HashMap<&str, i64>,
+) -> Result<Request, anyhow::Error> {
+    let app = application(app.kind, app.app_id.as_str(), app.app_key.as_deref())?;
+
     let usage = usages
         .into_iter()
         .map(|(k, v)| (k, format!("{}", v)))
@@ -278,19 +300,44 @@ pub(crate) fn build_call(
     let usage = Usage::new(usage.as_slice());
     let txn = Transaction::new(&app, None, Some(&usage), None);
     let txns = vec![txn];
-    let extensions = extensions::List::new().no_body();
 
-    let service = Service::new(
-        service.id(),
-        Credentials::ServiceToken(service.token().into()),
-    );
+    let service = threescale_service(service);
     let mut apicall = ApiCall::builder(&service);
     // the builder here can only fail if we fail to set a kind
-    let apicall = apicall
-        .transactions(&txns)
-        .extensions(&extensions)
-        .kind(Kind::AuthRep)
-        .build()?;
+    let apicall = apicall.transactions(&txns).kind(Kind::AuthRep).build()?;
+
+    Ok(Request::from(&apicall))
+}
+
+/// Builds a single report call for the usage of several applications of a service.
+pub(crate) fn build_report_call(
+    service: &crate::configuration::Service,
+    reports: &[Report],
+) -> Result<Request, anyhow::Error> {
+    let apps = reports
+        .iter()
+        // keys are only needed to authorize
+        .map(|(app, _)| application(app.kind, app.app_id.as_str(), None))
+        .collect::<Result<Vec<_>, _>>()?;
+    let usages = reports
+        .iter()
+        .map(|(_, usages)| {
+            let usage = usages
+                .iter()
+                .map(|(k, v)| (k.as_str(), format!("{}", v)))
+                .collect::<Vec<_>>();
+            Usage::new(usage.as_slice())
+        })
+        .collect::<Vec<_>>();
+    let txns = apps
+        .iter()
+        .zip(usages.iter())
+        .map(|(app, usage)| Transaction::new(app, None, Some(usage), None))
+        .collect::<Vec<_>>();
+
+    let service = threescale_service(service);
+    let mut apicall = ApiCall::builder(&service);
+    let apicall = apicall.transactions(&txns).kind(Kind::Report).build()?;
 
     Ok(Request::from(&apicall))
 }
@@ -0,0 +1,503 @@
+use core::convert::TryFrom;
+use core::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub(crate) enum ResponseError {
+    #[error("response body is not valid UTF-8")]
+    Utf8(#[source] core::str::Utf8Error),
+    #[error("error parsing XML response")]
+    Xml(#[source] roxmltree::Error),
+    #[error("unexpected element `{0}` in response")]
+    UnexpectedElement(String),
+    #[error("missing element `{0}` in response")]
+    MissingElement(&'static str),
+    #[error("invalid value `{1}` for `{0}` in response")]
+    InvalidValue(&'static str, String),
+}
+
+const LIMITS_EXCEEDED_REASON: &str = "usage limits are exceeded";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Period {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+    Eternity,
+}
+
+impl FromStr for Period {
+    type Err = ResponseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let period = match s {
+            "minute" => Period::Minute,
+            "hour" => Period::Hour,
+            "day" => Period::Day,
+            "week" => Period::Week,
+            "month" => Period::Month,
+            "year" => Period::Year,
+            "eternity" => Period::Eternity,
+            _ => return Err(ResponseError::InvalidValue("period", s.to_string())),
+        };
+
+        Ok(period)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UsageReport {
+    metric: String,
+    period: Period,
+    // period boundaries as seconds since the UNIX epoch, absent for eternity
+    period_start: Option<u64>,
+    period_end: Option<u64>,
+    max_value: u64,
+    current_value: u64,
+    // as flagged by the backend, since disabled metrics have a max_value of 0 without being
+    // the reason of a denial
+    exceeded: bool,
+}
+
+impl UsageReport {
+    pub fn metric(&self) -> &str {
+        self.metric.as_str()
+    }
+
+    pub fn period(&self) -> Period {
+        self.period
+    }
+
+    pub fn period_start(&self) -> Option<u64> {
+        self.period_start
+    }
+
+    pub fn period_end(&self) -> Option<u64> {
+        self.period_end
+    }
+
+    pub fn max_value(&self) -> u64 {
+        self.max_value
+    }
+
+    pub fn current_value(&self) -> u64 {
+        self.current_value
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.max_value.saturating_sub(self.current_value)
+    }
+
+    // the backend denied the request because of this limit
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AuthorizationStatus {
+    authorized: bool,
+    reason: Option<String>,
+    plan: Option<String>,
+    usage_reports: Vec<UsageReport>,
+}
+
+impl AuthorizationStatus {
+    pub fn is_authorized(&self) -> bool {
+        self.authorized
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn plan(&self) -> Option<&str> {
+        self.plan.as_deref()
+    }
+
+    pub fn usage_reports(&self) -> &Vec<UsageReport> {
+        self.usage_reports.as_ref()
+    }
+
+    /// Whether the request was rejected because of usage limits, according to the reason
+    /// given by the backend or the limits it flagged as exceeded.
+    pub fn is_limited(&self) -> bool {
+        !self.authorized
+            && (self.reason() == Some(LIMITS_EXCEEDED_REASON)
+                || self.usage_reports.iter().any(UsageReport::is_exceeded))
+    }
+
+    /// Seconds from `now` (since the UNIX epoch) until all exceeded limits are reset.
+    ///
+    /// Returns `None` if no limit is exceeded or if any of them never resets.
+    pub fn retry_after(&self, now: u64) -> Option<u64> {
+        self.usage_reports
+            .iter()
+            .filter(|report| report.is_exceeded())
+            .try_fold(None, |max: Option<u64>, report| {
+                let secs = report.period_end()?.saturating_sub(now).max(1);
+                Some(Some(max.map_or(secs, |max| max.max(secs))))
+            })
+            .flatten()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BackendError {
+    code: String,
+    message: String,
+}
+
+impl BackendError {
+    pub fn code(&self) -> &str {
+        self.code.as_str()
+    }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
+}
+
+/// Parsed body of an authrep (or authorize) call to the 3scale backend.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AuthRepResponse {
+    Status(AuthorizationStatus),
+    Error(BackendError),
+}
+
+impl AuthRepResponse {
+    pub fn is_authorized(&self) -> bool {
+        match self {
+            Self::Status(status) => status.is_authorized(),
+            Self::Error(_) => false,
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Status(status) => status.reason(),
+            Self::Error(error) => error.message().into(),
+        }
+    }
+
+    pub fn usage_reports(&self) -> Option<&Vec<UsageReport>> {
+        match self {
+            Self::Status(status) => status.usage_reports().into(),
+            Self::Error(_) => None,
+        }
+    }
+}
+
+impl TryFrom<&str> for AuthRepResponse {
+    type Error = ResponseError;
+
+    fn try_from(xml: &str) -> Result<Self, ResponseError> {
+        let doc = roxmltree::Document::parse(xml).map_err(ResponseError::Xml)?;
+        let root = doc.root_element();
+
+        match root.tag_name().name() {
+            "status" => parse_status(root).map(Self::Status),
+            "error" => Ok(Self::Error(BackendError {
+                code: root.attribute("code").unwrap_or_default().to_string(),
+                message: element_text(root).unwrap_or_default().to_string(),
+            })),
+            name => Err(ResponseError::UnexpectedElement(name.to_string())),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for AuthRepResponse {
+    type Error = ResponseError;
+
+    fn try_from(buf: &[u8]) -> Result<Self, ResponseError> {
+        let xml = core::str::from_utf8(buf).map_err(ResponseError::Utf8)?;
+        Self::try_from(xml)
+    }
+}
+
+fn element_text<'a>(node: roxmltree::Node<'a, '_>) -> Option<&'a str> {
+    node.text().map(str::trim)
+}
+
+fn child_elements<'a, 'i: 'a>(
+    node: roxmltree::Node<'a, 'i>,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'i>> {
+    node.children().filter(|n| n.is_element())
+}
+
+fn parse_number(name: &'static str, node: roxmltree::Node) -> Result<u64, ResponseError> {
+    let text = element_text(node).unwrap_or_default();
+    text.parse::<u64>()
+        .map_err(|_| ResponseError::InvalidValue(name, text.to_string()))
+}
+
+fn parse_status(node: roxmltree::Node) -> Result<AuthorizationStatus, ResponseError> {
+    let mut authorized = None;
+    let mut reason = None;
+    let mut plan = None;
+    let mut usage_reports = vec![];
+
+    for child in child_elements(node) {
+        match child.tag_name().name() {
+            "authorized" => {
+                authorized = match element_text(child) {
+                    Some("true") => Some(true),
+                    Some("false") => Some(false),
+                    other => {
+                        return Err(ResponseError::InvalidValue(
+                            "authorized",
+                            other.unwrap_or_default().to_string(),
+                        ))
+                    }
+                }
+            }
+            "reason" => reason = element_text(child).map(str::to_string),
+            "plan" => plan = element_text(child).map(str::to_string),
+            "usage_reports" => {
+                for report in child_elements(child).filter(|n| n.has_tag_name("usage_report")) {
+                    usage_reports.push(parse_usage_report(report)?);
+                }
+            }
+            // other elements such as application or user_plan are ignored
+            _ => (),
+        }
+    }
+
+    Ok(AuthorizationStatus {
+        authorized: authorized.ok_or(ResponseError::MissingElement("authorized"))?,
+        reason,
+        plan,
+        usage_reports,
+    })
+}
+
+fn parse_usage_report(node: roxmltree::Node) -> Result<UsageReport, ResponseError> {
+    let metric = node
+        .attribute("metric")
+        .ok_or(ResponseError::MissingElement("metric"))?
+        .to_string();
+    let period = node
+        .attribute("period")
+        .ok_or(ResponseError::MissingElement("period"))?
+        .parse::<Period>()?;
+    let exceeded = node.attribute("exceeded") == Some("true");
+
+    let mut period_start = None;
+    let mut period_end = None;
+    let mut max_value = None;
+    let mut current_value = None;
+
+    for child in child_elements(node) {
+        match child.tag_name().name() {
+            "period_start" => {
+                period_start = Some(parse_timestamp(element_text(child).unwrap_or_default())?)
+            }
+            "period_end" => {
+                period_end = Some(parse_timestamp(element_text(child).unwrap_or_default())?)
+            }
+            "max_value" => max_value = Some(parse_number("max_value", child)?),
+            "current_value" => current_value = Some(parse_number("current_value", child)?),
+            _ => (),
+        }
+    }
+
+    Ok(UsageReport {
+        metric,
+        period,
+        period_start,
+        period_end,
+        max_value: max_value.ok_or(ResponseError::MissingElement("max_value"))?,
+        current_value: current_value.ok_or(ResponseError::MissingElement("current_value"))?,
+        exceeded,
+    })
+}
+
+// Parses timestamps as sent by the 3scale backend, ie. "2021-03-01 10:00:00 +0000",
+// into seconds since the UNIX epoch.
+fn parse_timestamp(ts: &str) -> Result<u64, ResponseError> {
+    let invalid = || ResponseError::InvalidValue("timestamp", ts.to_string());
+
+    let mut parts = ts.split_whitespace();
+    let (date, time, offset) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(date), Some(time), offset, None) => (date, time, offset.unwrap_or("+0000")),
+        _ => return Err(invalid()),
+    };
+
+    let numbers = |s: &str, sep: char| -> Option<Vec<i64>> {
+        s.split(sep).map(|n| n.parse::<i64>().ok()).collect()
+    };
+
+    // bounded so that the arithmetic below cannot overflow
+    let (year, month, day) = match numbers(date, '-').as_deref() {
+        Some(&[y, m, d])
+            if (0..=9999).contains(&y) && (1..=12).contains(&m) && (1..=31).contains(&d) =>
+        {
+            (y, m, d)
+        }
+        _ => return Err(invalid()),
+    };
+    let (hour, minute, second) = match numbers(time, ':').as_deref() {
+        Some(&[h, m, s])
+            if (0..24).contains(&h) && (0..60).contains(&m) && (0..61).contains(&s) =>
+        {
+            (h, m, s)
+        }
+        _ => return Err(invalid()),
+    };
+    // the offset comes from the network, so it is not sliced before checking it is "+hhmm"
+    let offset_secs = match offset.as_bytes() {
+        &[sign @ b'+', h1, h2, m1, m2] | &[sign @ b'-', h1, h2, m1, m2]
+            if [h1, h2, m1, m2].iter().all(u8::is_ascii_digit) =>
+        {
+            let digit = |b: u8| i64::from(b - b'0');
+            let secs = (digit(h1) * 10 + digit(h2)) * 3600 + (digit(m1) * 10 + digit(m2)) * 60;
+            if sign == b'-' {
+                -secs
+            } else {
+                secs
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    // days since the epoch for the proleptic Gregorian calendar (H. Hinnant's days_from_civil)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second - offset_secs;
+    if secs < 0 {
+        return Err(invalid());
+    }
+
+    Ok(secs as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    mod fixtures {
+        pub const AUTHORIZED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>true</authorized>
+  <plan>Basic</plan>
+  <usage_reports>
+    <usage_report metric="hits" period="minute">
+      <period_start>2021-03-01 10:00:00 +0000</period_start>
+      <period_end>2021-03-01 10:01:00 +0000</period_end>
+      <max_value>5</max_value>
+      <current_value>2</current_value>
+    </usage_report>
+    <usage_report metric="ticks" period="eternity">
+      <max_value>100</max_value>
+      <current_value>7</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#;
+
+        pub const LIMITS_EXCEEDED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>false</authorized>
+  <reason>usage limits are exceeded</reason>
+  <plan>Basic</plan>
+  <usage_reports>
+    <usage_report metric="hits" period="day" exceeded="true">
+      <period_start>2021-03-01 00:00:00 +0100</period_start>
+      <period_end>2021-03-02 00:00:00 +0100</period_end>
+      <max_value>5</max_value>
+      <current_value>5</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#;
+
+        pub const INVALID_KEY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<error code="user_key_invalid">user key "foo" is invalid</error>"#;
+    }
+
+    #[test]
+    fn it_parses_an_authorized_response() {
+        let response = AuthRepResponse::try_from(fixtures::AUTHORIZED);
+        assert!(response.is_ok());
+        let response = response.unwrap();
+        assert!(response.is_authorized());
+        assert_eq!(response.reason(), None);
+
+        let status = match response {
+            AuthRepResponse::Status(status) => status,
+            _ => unreachable!(),
+        };
+        assert_eq!(status.plan(), Some("Basic"));
+        let reports = status.usage_reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].metric(), "hits");
+        assert_eq!(reports[0].period(), Period::Minute);
+        assert_eq!(reports[0].period_start(), Some(1_614_592_800));
+        assert_eq!(reports[0].period_end(), Some(1_614_592_860));
+        assert_eq!(reports[0].remaining(), 3);
+        assert!(!reports[0].is_exceeded());
+        assert_eq!(reports[1].period(), Period::Eternity);
+        assert_eq!(reports[1].period_end(), None);
+    }
+
+    #[test]
+    fn it_parses_a_limits_exceeded_response() {
+        let response = AuthRepResponse::try_from(fixtures::LIMITS_EXCEEDED.as_bytes());
+        assert!(response.is_ok());
+        let response = response.unwrap();
+        assert!(!response.is_authorized());
+        assert_eq!(response.reason(), Some("usage limits are exceeded"));
+        let reports = response.usage_reports().unwrap();
+        assert!(reports[0].is_exceeded());
+        // +0100 offset
+        assert_eq!(reports[0].period_end(), Some(1_614_639_600));
+    }
+
+    #[test]
+    fn it_parses_an_error_response() {
+        let response = AuthRepResponse::try_from(fixtures::INVALID_KEY);
+        assert!(response.is_ok());
+        match response.unwrap() {
+            AuthRepResponse::Error(error) => {
+                assert_eq!(error.code(), "user_key_invalid");
+                assert_eq!(error.message(), r#"user key "foo" is invalid"#);
+            }
+            r => panic!("unexpected response {:#?}", r),
+        }
+    }
+
+    #[test]
+    fn it_rejects_malformed_timestamps() {
+        assert_eq!(
+            parse_timestamp("2021-03-01 10:00:00 -0130").ok(),
+            Some(1_614_598_200)
+        );
+        for ts in [
+            "2021-03-01 10:00:00 +0é0",
+            "2021-03-01 10:00:00 +é00",
+            "2021-03-01 10:00:00 +00",
+            "2021-03-01 10:00:00 0000+",
+            "2021-03-01 10:-5:00 +0000",
+            "99999999999999-03-01 10:00:00 +0000",
+            "2021-03-01",
+        ]
+        .iter()
+        {
+            assert!(parse_timestamp(ts).is_err(), "{}", ts);
+        }
+    }
+
+    #[test]
+    fn it_rejects_garbage() {
+        assert!(AuthRepResponse::try_from("<html>nope</html>").is_err());
+        assert!(AuthRepResponse::try_from("not xml").is_err());
+        assert!(AuthRepResponse::try_from("<status><plan>x</plan></status>").is_err());
+    }
+}
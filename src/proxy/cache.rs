@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::authrep::response::UsageReport;
+use super::state::AppRef;
+
+/// Identifies cached authorizations: an application of a service using a set of metrics.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    app: AppRef,
+    // sorted metric names
+    metrics: Vec<String>,
+}
+
+impl CacheKey {
+    pub fn new(app: &AppRef, usages: &HashMap<String, i64>) -> Self {
+        let mut metrics = usages.keys().cloned().collect::<Vec<_>>();
+        metrics.sort_unstable();
+
+        Self {
+            app: app.clone(),
+            metrics,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Limit {
+    metric: String,
+    // seconds since the UNIX epoch at which the limit is reset, if ever
+    period_end: Option<u64>,
+    remaining: u64,
+}
+
+/// An authorization given by the backend, along with the limits left according to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    expires_at: u64,
+    limits: Vec<Limit>,
+}
+
+impl Entry {
+    /// Records an authorization valid for `ttl` seconds.
+    pub fn new(usage_reports: &[UsageReport], now: u64, ttl: u64) -> Self {
+        let limits = usage_reports
+            .iter()
+            .map(|report| Limit {
+                metric: report.metric().to_string(),
+                period_end: report.period_end(),
+                remaining: report.remaining(),
+            })
+            .collect();
+
+        Self {
+            expires_at: now.saturating_add(ttl),
+            limits,
+        }
+    }
+
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    /// Authorizes the usage locally, deducting it from the cached limits.
+    ///
+    /// Returns `false` when the backend has to be asked: the entry expired, some limit
+    /// period has ended since it was cached, or not enough is left of some limit.
+    pub fn authorize(&mut self, usages: &HashMap<String, i64>, now: u64) -> bool {
+        if self.expires_at <= now {
+            return false;
+        }
+
+        let fits = self.limits.iter().all(|limit| {
+            let delta = usages.get(limit.metric.as_str()).copied().unwrap_or(0);
+            limit.period_end.map(|end| end > now).unwrap_or(true)
+                && (delta <= 0 || (delta as u64) <= limit.remaining)
+        });
+        if !fits {
+            return false;
+        }
+
+        for limit in self.limits.iter_mut() {
+            if let Some(&delta) = usages.get(limit.metric.as_str()) {
+                if delta > 0 {
+                    limit.remaining -= delta as u64;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// When each cached entry expires, by the shared data key it is stored under, so that
+/// entries can be capped and expired without reading them all.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct CacheIndex {
+    // sorted so that an index serializes the same way until it changes
+    entries: BTreeMap<String, u64>,
+}
+
+impl CacheIndex {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Adds an entry expiring at `expires_at`, evicting the expired entries and then the
+    /// one closest to expiring if there are already `max_entries`. Returns the keys of the
+    /// evicted entries.
+    pub fn insert(
+        &mut self,
+        key: String,
+        expires_at: u64,
+        now: u64,
+        max_entries: usize,
+    ) -> Vec<String> {
+        let mut evicted = vec![];
+        if !self.entries.contains_key(&key) && self.entries.len() >= max_entries {
+            evicted = self.expire(now);
+            if self.entries.len() >= max_entries {
+                let closest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, &expires_at)| expires_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(closest) = closest {
+                    self.entries.remove(&closest);
+                    evicted.push(closest);
+                }
+            }
+        }
+
+        self.entries.insert(key, expires_at);
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    /// Drops the entries that expired, returning their keys.
+    pub fn expire(&mut self, now: u64) -> Vec<String> {
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, &expires_at)| expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired.iter() {
+            self.entries.remove(key);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::authrep::response::AuthRepResponse;
+    use core::convert::TryFrom;
+
+    const NOW: u64 = 1_614_592_830;
+
+    fn hits(delta: i64) -> HashMap<String, i64> {
+        vec![("hits".to_string(), delta)].into_iter().collect()
+    }
+
+    fn usage_reports(current_value: u64) -> Vec<UsageReport> {
+        let body = format!(
+            r#"<status>
+  <authorized>true</authorized>
+  <usage_reports>
+    <usage_report metric="hits" period="minute">
+      <period_start>2021-03-01 10:00:00 +0000</period_start>
+      <period_end>2021-03-01 10:01:00 +0000</period_end>
+      <max_value>5</max_value>
+      <current_value>{}</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#,
+            current_value
+        );
+        AuthRepResponse::try_from(body.as_str())
+            .unwrap()
+            .usage_reports()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn it_authorizes_within_cached_limits() {
+        let mut entry = Entry::new(&usage_reports(2), NOW, 10);
+        assert!(entry.authorize(&hits(2), NOW));
+        assert!(!entry.authorize(&hits(2), NOW));
+        assert!(entry.authorize(&hits(1), NOW));
+        assert!(!entry.authorize(&hits(1), NOW));
+    }
+
+    #[test]
+    fn it_misses_on_expired_entries_and_ended_periods() {
+        let mut entry = Entry::new(&usage_reports(0), NOW, 10);
+        assert!(!entry.authorize(&hits(1), NOW + 10));
+
+        // the minute period ends 30 seconds after NOW
+        let mut entry = Entry::new(&usage_reports(0), NOW, 60);
+        assert!(entry.authorize(&hits(1), NOW + 29));
+        assert!(!entry.authorize(&hits(1), NOW + 30));
+    }
+
+    #[test]
+    fn it_caps_the_number_of_entries() {
+        let mut index = CacheIndex::default();
+        assert!(index.insert("foo".into(), NOW + 10, NOW, 2).is_empty());
+        assert!(index.insert("bar".into(), NOW + 20, NOW, 2).is_empty());
+        assert_eq!(index.insert("baz".into(), NOW + 20, NOW, 2), vec!["foo"]);
+        assert!(index.insert("baz".into(), NOW + 30, NOW, 2).is_empty());
+        assert_eq!(index.len(), 2);
+
+        // expired entries make room first
+        assert_eq!(
+            index.insert("foo".into(), NOW + 30, NOW + 20, 2),
+            vec!["bar"]
+        );
+        assert_eq!(index.expire(NOW + 30).len(), 2);
+        assert_eq!(index.len(), 0);
+    }
+}
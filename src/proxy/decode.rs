@@ -4,7 +4,7 @@ use std::borrow::Cow;
 use thiserror::Error;
 
 use crate::configuration::Decode;
-use crate::util::pairs::Pairs;
+use crate::util::pairs::PairsRef;
 
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Metadata {
@@ -26,7 +26,6 @@ pub(crate) enum ValueError<'a> {
     #[error("error decoding JSON")]
     DecodeJSON(Value<'a>, #[source] serde_json::Error),
     #[error("error decoding pairs")]
-    #[allow(dead_code)]
     DecodePairs(Value<'a>),
 }
 
@@ -44,8 +43,54 @@ pub(crate) enum Value<'a> {
     //JsonString(serde_json::Value::String),
     //JsonList(serde_json::Value::Array(Vec<serde_json::Value>)),
     //JsonObject(serde_json::Value::Object(serde_json::Map<String, serde_json::Value>)),
-    #[allow(dead_code)]
-    PairsValue(Pairs),
+    // serialized pairs, walked in place by `get_path`
+    PairsValue(Cow<'a, [u8]>),
+}
+
+/// A value found at the end of a path walked into a decoded value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Leaf {
+    Null,
+    Bool(bool),
+    // kept as JSON numbers so that integers are not rounded through f64
+    Number(serde_json::Number),
+    String(String),
+    // unstructured values that are not valid UTF-8
+    Bytes(Vec<u8>),
+    List(Vec<Leaf>),
+}
+
+impl Leaf {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Leaf::String(s.to_string()),
+            Err(_) => Leaf::Bytes(bytes.to_vec()),
+        }
+    }
+
+    /// Returns the leaf as a credential, which has to be a string or an integral number.
+    ///
+    /// Numbers that are only held as floating point, such as protobuf ones or JSON integers
+    /// too large for 64 bits, have to be integers that floating point represents exactly,
+    /// so that no credential is mistaken for a nearby one.
+    pub fn to_credential(&self) -> Option<String> {
+        // 2^53, over which not every integer can be represented as f64
+        const MAX_EXACT_F64: f64 = 9_007_199_254_740_992.0;
+
+        match self {
+            Leaf::String(s) => Some(s.clone()),
+            Leaf::Number(n) => n
+                .as_u64()
+                .map(|n| n.to_string())
+                .or_else(|| n.as_i64().map(|n| n.to_string()))
+                .or_else(|| {
+                    n.as_f64()
+                        .filter(|n| n.fract() == 0.0 && n.abs() <= MAX_EXACT_F64)
+                        .map(|n| format!("{}", n as i64))
+                }),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> Value<'a> {
@@ -53,24 +98,9 @@ impl<'a> Value<'a> {
         match self {
             Value::String(s) => Some(s.into_owned()),
             Value::Bytes(b) => String::from_utf8(b.into_owned()).ok(),
-            Value::PairsValue(_p) => {
-                log::error!("need to implement Pairs -> String conversion");
-                unimplemented!("need to implement Pairs -> String conversion");
-            }
             Value::JsonValue(json) => json.as_str().map(|s| s.to_string()),
-            Value::ProtoValue(mut _proto) => {
-                //if proto.has_string_value() {
-                //    //proto.take_string_value().into()
-                //    None
-                //} else if proto.has_struct_value() {
-                //let s = proto.take_struct_value();
-                //log::warn!("STRUCT FOUND?"); //: {:#?}", s);
-                //} else {
-                //    None
-                //}
-                log::error!("need to implement Protobuf -> String conversion");
-                unimplemented!("need to implement Protobuf -> String conversion");
-            }
+            // structured values are walked with `get_path` instead
+            Value::PairsValue(_) | Value::ProtoValue(_) => None,
         }
     }
 
@@ -83,13 +113,8 @@ impl<'a> Value<'a> {
             None => return Err(ValueError::Type(self)),
         };
 
-        let hex = bytes
-            .iter()
-            .map(|c| format!("0x{:02x}", *c))
-            .collect::<Vec<_>>()
-            .join(", ");
-        log::debug!("Decoding {} bytes: [{}]", bytes.len(), hex);
         let decode = decode.unwrap();
+        log::trace!("decoding {} bytes as {:?}", bytes.len(), decode);
         let value = match decode {
             Decode::Base64Decode => Value::Bytes(Cow::from(
                 base64::decode_config(bytes, base64::STANDARD)
@@ -99,30 +124,10 @@ impl<'a> Value<'a> {
                 base64::decode_config(bytes, base64::URL_SAFE)
                     .map_err(|e| ValueError::DecodeBase64(self, e))?,
             )),
-            Decode::ProtobufValue => {
-                //let proto = {
-                //    let mut cis = protobuf::CodedInputStream::from_bytes(bytes);
-                //    cis.read_message::<protobuf::well_known_types::Struct>()
-                //};
-                //let proto = <prost_types::Struct as prost::Message>::decode(bytes);
-                let proto = Metadata::decode(bytes);
-
-                log::warn!("protobuf parsing result: {:#?}", proto);
-                match proto {
-                    Ok(value) => {
-                        //let type_id = value.type_id();
-                        //log::warn!("protobuf type id {:#?}", type_id);
-                        //if value.has_struct_value() {
-                        //    log::warn!("protobuf has struct")
-                        //} else {
-                        //    log::warn!("protobuf has struct FAILED")
-                        //}
-                        log::warn!("===> parsed ok!!!");
-                        Value::ProtoValue(value)
-                    }
-                    Err(e) => Err(ValueError::DecodeProtobuf(self, e))?,
-                }
-            }
+            Decode::ProtobufValue => match Metadata::decode(bytes) {
+                Ok(value) => Value::ProtoValue(value),
+                Err(e) => Err(ValueError::DecodeProtobuf(self, e))?,
+            },
             Decode::JsonValue => {
                 let json = serde_json::from_slice::<serde_json::Value>(bytes);
                 match json {
@@ -130,6 +135,10 @@ impl<'a> Value<'a> {
                     Err(e) => Err(ValueError::DecodeJSON(self, e))?,
                 }
             }
+            Decode::PairsValue => match PairsRef::new(bytes) {
+                Ok(_) => Value::PairsValue(self.into_bytes()),
+                Err(_) => Err(ValueError::DecodePairs(self))?,
+            },
         };
 
         Ok(value)
@@ -147,6 +156,70 @@ impl<'a> Value<'a> {
         }
     }
 
+    /// Walks into a decoded value through a path of field names and list indexes, returning
+    /// the leaf it leads to.
+    ///
+    /// The first segment of a protobuf metadata path selects a filter in `filter_metadata`.
+    /// Values that are not structured are only reached by an empty path, and paths leading
+    /// to objects, structs or nested pairs are not leaves.
+    pub fn get_path<S: AsRef<str>>(&self, path: &[S]) -> Option<Leaf> {
+        let mut segments = path.iter().map(AsRef::as_ref);
+
+        match self {
+            Value::Bytes(b) if path.is_empty() => Some(Leaf::from_bytes(b.as_ref())),
+            Value::String(s) if path.is_empty() => Some(Leaf::String(s.to_string())),
+            Value::Bytes(_) | Value::String(_) => None,
+            Value::JsonValue(json) => segments
+                .try_fold(json, |json, segment| json_field(json, segment))
+                .and_then(json_leaf),
+            Value::ProtoValue(metadata) => {
+                let filter = metadata.filter_metadata.get(segments.next()?)?;
+                let first = proto_field(filter, segments.next()?)?;
+                segments
+                    .try_fold(first, |proto, segment| proto_value_field(proto, segment))
+                    .and_then(proto_leaf)
+            }
+            Value::PairsValue(b) => {
+                let pairs = PairsRef::new(b.as_ref()).ok()?;
+                let first = pairs.get(segments.next()?)?;
+                let value = segments.try_fold(first, |value, segment| {
+                    PairsRef::nested(value)?.get(segment)
+                })?;
+                match PairsRef::nested(value) {
+                    Some(_) => None,
+                    None => Some(Leaf::from_bytes(value)),
+                }
+            }
+        }
+    }
+
+    /// Looks up a credential key in a decoded value, returning it as a value of its own.
+    ///
+    /// Keys starting with `/` are JSON pointers walking into nested objects and arrays, such
+    /// as `/envoy.filters.http.jwt_authn/verified_jwt/azp` in metadata, while other keys are
+    /// the name of a field. Values that are not structured are the credential themselves.
+    pub fn lookup(&self, key: &str) -> Option<Value<'static>> {
+        let leaf = match self {
+            Value::Bytes(_) | Value::String(_) => self.get_path::<&str>(&[]),
+            _ => self.get_path(key_segments(key).as_slice()),
+        }?;
+
+        match leaf {
+            Leaf::Bytes(b) => Some(Value::Bytes(Cow::Owned(b))),
+            leaf => leaf.to_credential().map(|s| Value::String(Cow::Owned(s))),
+        }
+    }
+
+    // Only called on values `as_bytes` returns bytes for.
+    fn into_bytes(self) -> Cow<'a, [u8]> {
+        match self {
+            Value::Bytes(b) => b,
+            Value::String(Cow::Borrowed(s)) => Cow::Borrowed(s.as_bytes()),
+            Value::String(Cow::Owned(s)) => Cow::Owned(s.into_bytes()),
+            _ => Cow::Borrowed(&[]),
+        }
+    }
+
     fn as_bytes(&self) -> Option<&[u8]> {
         match self {
             Value::Bytes(v) => v.as_ref().into(),
@@ -155,3 +228,222 @@ impl<'a> Value<'a> {
         }
     }
 }
+
+// Splits a key into the fields it walks through, unescaping JSON pointer segments.
+fn key_segments(key: &str) -> Vec<String> {
+    match key.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => vec![key.to_string()],
+    }
+}
+
+fn json_field<'j>(json: &'j serde_json::Value, segment: &str) -> Option<&'j serde_json::Value> {
+    match json {
+        serde_json::Value::Object(map) => map.get(segment),
+        serde_json::Value::Array(list) => list.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn json_leaf(json: &serde_json::Value) -> Option<Leaf> {
+    match json {
+        serde_json::Value::Null => Some(Leaf::Null),
+        serde_json::Value::Bool(b) => Some(Leaf::Bool(*b)),
+        serde_json::Value::Number(n) => Some(Leaf::Number(n.clone())),
+        serde_json::Value::String(s) => Some(Leaf::String(s.clone())),
+        serde_json::Value::Array(list) => list
+            .iter()
+            .map(json_leaf)
+            .collect::<Option<_>>()
+            .map(Leaf::List),
+        serde_json::Value::Object(_) => None,
+    }
+}
+
+fn proto_field<'p>(
+    proto: &'p prost_types::Struct,
+    segment: &str,
+) -> Option<&'p prost_types::Value> {
+    proto.fields.get(segment)
+}
+
+fn proto_value_field<'p>(
+    proto: &'p prost_types::Value,
+    segment: &str,
+) -> Option<&'p prost_types::Value> {
+    use prost_types::value::Kind;
+
+    match proto.kind.as_ref()? {
+        Kind::StructValue(st) => proto_field(st, segment),
+        Kind::ListValue(list) => list.values.get(segment.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+fn proto_leaf(proto: &prost_types::Value) -> Option<Leaf> {
+    use prost_types::value::Kind;
+
+    match proto.kind.as_ref() {
+        None | Some(Kind::NullValue(_)) => Some(Leaf::Null),
+        // not a number, nor a leaf, when it is not finite
+        Some(Kind::NumberValue(n)) => serde_json::Number::from_f64(*n).map(Leaf::Number),
+        Some(Kind::StringValue(s)) => Some(Leaf::String(s.clone())),
+        Some(Kind::BoolValue(b)) => Some(Leaf::Bool(*b)),
+        Some(Kind::StructValue(_)) => None,
+        Some(Kind::ListValue(list)) => list
+            .values
+            .iter()
+            .map(proto_leaf)
+            .collect::<Option<_>>()
+            .map(Leaf::List),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::pairs::Pairs;
+
+    #[test]
+    fn it_looks_up_keys_in_decoded_values() {
+        let json = Value::String(r#"{"azp": "app", "nested": {"a/b": ["x", "y"]}}"#.into())
+            .decode(Some(Decode::JsonValue))
+            .unwrap();
+        assert_eq!(
+            json.lookup("azp").and_then(Value::to_string).as_deref(),
+            Some("app")
+        );
+        assert_eq!(
+            json.lookup("/nested/a~1b/1")
+                .and_then(Value::to_string)
+                .as_deref(),
+            Some("y")
+        );
+        assert!(json.lookup("aud").is_none());
+        assert!(json.lookup("/azp/deeper").is_none());
+
+        let plain = Value::String("app".into());
+        assert_eq!(
+            plain.lookup("azp").and_then(Value::to_string).as_deref(),
+            Some("app")
+        );
+    }
+
+    #[test]
+    fn it_walks_paths_to_typed_leaves() {
+        use prost_types::value::Kind;
+
+        let json = Value::JsonValue(serde_json::json!({"exp": 1600000000, "scope": ["a", true]}));
+        assert_eq!(
+            json.get_path(&["exp"]),
+            Some(Leaf::Number(1600000000.into()))
+        );
+        assert_eq!(
+            json.get_path(&["scope"]),
+            Some(Leaf::List(vec![Leaf::String("a".into()), Leaf::Bool(true)]))
+        );
+        assert_eq!(json.get_path::<&str>(&[]), None);
+
+        let string = |s: &str| prost_types::Value {
+            kind: Some(Kind::StringValue(s.to_string())),
+        };
+        let jwt = prost_types::Struct {
+            fields: vec![("azp".to_string(), string("my-client"))]
+                .into_iter()
+                .collect(),
+        };
+        let verified = prost_types::Struct {
+            fields: vec![(
+                "verified_jwt".to_string(),
+                prost_types::Value {
+                    kind: Some(Kind::StructValue(jwt)),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let proto = Value::ProtoValue(Metadata {
+            filter_metadata: vec![("jwt_authn".to_string(), verified)]
+                .into_iter()
+                .collect(),
+        });
+        assert_eq!(
+            proto.get_path(&["jwt_authn", "verified_jwt", "azp"]),
+            Some(Leaf::String("my-client".into()))
+        );
+        assert_eq!(proto.get_path(&["jwt_authn", "verified_jwt"]), None);
+        assert_eq!(proto.get_path(&["other", "verified_jwt"]), None);
+        assert_eq!(
+            proto
+                .lookup("/jwt_authn/verified_jwt/azp")
+                .and_then(Value::to_string)
+                .as_deref(),
+            Some("my-client")
+        );
+        assert!(proto.clone().to_string().is_none());
+
+        let jwt = Pairs::new(vec![
+            ("azp".to_string(), b"my-client".to_vec()),
+            ("raw".to_string(), vec![0xff, 0x00]),
+        ]);
+        let metadata = Pairs::new(vec![("jwt".to_string(), jwt.to_bytes().unwrap())]);
+        let pairs = Value::Bytes(metadata.to_bytes().unwrap().into())
+            .decode(Some(Decode::PairsValue))
+            .unwrap();
+        assert_eq!(
+            pairs.get_path(&["jwt", "azp"]),
+            Some(Leaf::String("my-client".into()))
+        );
+        assert_eq!(
+            pairs.get_path(&["jwt", "raw"]),
+            Some(Leaf::Bytes(vec![0xff, 0x00]))
+        );
+        assert_eq!(pairs.get_path(&["jwt"]), None);
+        assert_eq!(pairs.get_path(&["jwt", "azp", "deeper"]), None);
+        assert!(matches!(
+            Value::String("no".into()).decode(Some(Decode::PairsValue)),
+            Err(ValueError::DecodePairs(_))
+        ));
+        assert!(pairs.to_string().is_none());
+
+        assert_eq!(Leaf::Bool(true).to_credential(), None);
+    }
+
+    #[test]
+    fn it_takes_integral_numbers_as_credentials() {
+        let credential = |json: &str| {
+            Value::String(json.into())
+                .decode(Some(Decode::JsonValue))
+                .unwrap()
+                .get_path::<&str>(&["id"])
+                .and_then(|leaf| leaf.to_credential())
+        };
+        assert_eq!(credential(r#"{"id": 42}"#).as_deref(), Some("42"));
+        assert_eq!(credential(r#"{"id": -42}"#).as_deref(), Some("-42"));
+        assert_eq!(
+            credential(r#"{"id": 9007199254740993}"#).as_deref(),
+            Some("9007199254740993")
+        );
+        assert_eq!(
+            credential(r#"{"id": 18446744073709551615}"#).as_deref(),
+            Some("18446744073709551615")
+        );
+        assert_eq!(credential(r#"{"id": 42.0}"#).as_deref(), Some("42"));
+        assert_eq!(credential(r#"{"id": 4.2}"#), None);
+        assert_eq!(credential(r#"{"id": 18446744073709551616}"#), None);
+
+        let proto = |n: f64| {
+            proto_leaf(&prost_types::Value {
+                kind: Some(prost_types::value::Kind::NumberValue(n)),
+            })
+            .and_then(|leaf| leaf.to_credential())
+        };
+        assert_eq!(proto(42.0).as_deref(), Some("42"));
+        assert_eq!(proto(4.2), None);
+        assert_eq!(proto(2f64.powi(60)), None);
+        assert_eq!(proto(f64::NAN), None);
+    }
+}
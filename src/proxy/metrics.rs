@@ -0,0 +1,68 @@
+// Metrics of the filter, which the host exposes along with its own, eg. in Envoy's stats.
+use log::warn;
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MetricType;
+
+/// Counters kept by the filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    // requests matching no mapping rule, by the decision of the unmatched policy
+    UnmatchedDenied,
+    UnmatchedAllowed,
+    UnmatchedReported,
+    // usage of applications over the pending cap, left queued for the next flush
+    UsageDeferred,
+    // usage that could not be queued or reported at all
+    UsageDropped,
+}
+
+const COUNTERS: [Counter; 5] = [
+    Counter::UnmatchedDenied,
+    Counter::UnmatchedAllowed,
+    Counter::UnmatchedReported,
+    Counter::UsageDeferred,
+    Counter::UsageDropped,
+];
+
+impl Counter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::UnmatchedDenied => "threescale_wasm_auth.unmatched.denied",
+            Counter::UnmatchedAllowed => "threescale_wasm_auth.unmatched.allowed",
+            Counter::UnmatchedReported => "threescale_wasm_auth.unmatched.reported",
+            Counter::UsageDeferred => "threescale_wasm_auth.usage.deferred",
+            Counter::UsageDropped => "threescale_wasm_auth.usage.dropped",
+        }
+    }
+}
+
+/// Ids of the metrics as defined in the host for this VM, missing for those that could not be.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Metrics {
+    counters: [Option<u32>; COUNTERS.len()],
+}
+
+impl Metrics {
+    pub fn define() -> Self {
+        let mut metrics = Self::default();
+        for (id, &counter) in metrics.counters.iter_mut().zip(COUNTERS.iter()) {
+            *id = hostcalls::define_metric(MetricType::Counter, counter.name())
+                .map_err(|e| warn!("could not define metric {}: {:?}", counter.name(), e))
+                .ok();
+        }
+
+        metrics
+    }
+
+    pub fn increment(&self, counter: Counter) {
+        self.add(counter, 1)
+    }
+
+    pub fn add(&self, counter: Counter, count: usize) {
+        if let Some(id) = self.counters[counter as usize] {
+            if let Err(e) = hostcalls::increment_metric(id, count as i64) {
+                warn!("could not increment metric {}: {:?}", counter.name(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use log::info;
+use proxy_wasm::traits::HttpContext;
+
+use super::authrep::response::{AuthRepResponse, ResponseError};
+use crate::configuration::{DenyResponse, Responses};
+
+/// Status, headers and body to send downstream for a rejection.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Reply {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The different ways in which a request can end up being rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Rejection {
+    NoServiceMatched,
+    NoMappingRuleMatched,
+    CredentialsMissing,
+    Denied(Option<String>),
+    LimitsExceeded {
+        reason: Option<String>,
+        // seconds until the exceeded limits are reset, if they ever are
+        retry_after: Option<u64>,
+    },
+    BackendUnavailable,
+    NotConfigured,
+}
+
+impl Rejection {
+    pub fn status(&self, backend_failure_status: u32) -> u32 {
+        match self {
+            Self::CredentialsMissing => 401,
+            Self::NoMappingRuleMatched => 404,
+            Self::NoServiceMatched | Self::Denied(_) => 403,
+            Self::LimitsExceeded { .. } => 429,
+            Self::BackendUnavailable => backend_failure_status,
+            Self::NotConfigured => 503,
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::NoServiceMatched => "no known service matched",
+            Self::NoMappingRuleMatched => "no mapping rule matched",
+            Self::CredentialsMissing => "no credentials found in request",
+            Self::Denied(reason) => reason.as_deref().unwrap_or("access denied"),
+            Self::LimitsExceeded { reason, .. } => {
+                reason.as_deref().unwrap_or("usage limits are exceeded")
+            }
+            Self::BackendUnavailable => "3scale backend unavailable",
+            Self::NotConfigured => "no configuration loaded",
+        }
+    }
+
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::LimitsExceeded {
+                retry_after: Some(secs),
+                ..
+            } => vec![("retry-after", secs.to_string())],
+            _ => vec![],
+        }
+    }
+
+    pub fn body(&self) -> &'static [u8] {
+        match self {
+            Self::CredentialsMissing => b"Authentication required.\n",
+            Self::NoMappingRuleMatched => b"No mapping rule matched.\n",
+            Self::NoServiceMatched | Self::Denied(_) => b"Access forbidden.\n",
+            Self::LimitsExceeded { .. } => b"Too many requests.\n",
+            Self::BackendUnavailable | Self::NotConfigured => b"Service unavailable.\n",
+        }
+    }
+
+    pub fn deny_response<'a>(&self, responses: &'a Responses) -> Option<&'a DenyResponse> {
+        match self {
+            Self::NoServiceMatched => responses.no_service_matched(),
+            Self::NoMappingRuleMatched => responses.no_mapping_rule_matched(),
+            Self::CredentialsMissing => responses.credentials_missing(),
+            Self::Denied(_) => responses.auth_denied(),
+            Self::LimitsExceeded { .. } => responses.limits_exceeded(),
+            Self::BackendUnavailable => responses.backend_unavailable(),
+            Self::NotConfigured => None,
+        }
+    }
+
+    /// Builds the reply for this rejection, using the configured response if any and
+    /// filling in whatever it does not specify with the defaults.
+    pub fn reply(
+        &self,
+        deny_response: Option<&DenyResponse>,
+        backend_failure_status: u32,
+        request_id: Option<&str>,
+    ) -> Reply {
+        let status = deny_response
+            .and_then(DenyResponse::status)
+            .unwrap_or_else(|| self.status(backend_failure_status));
+
+        let mut headers = deny_response
+            .and_then(DenyResponse::headers)
+            .cloned()
+            .unwrap_or_default();
+        headers.extend(
+            self.headers()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value)),
+        );
+
+        let body = match deny_response.and_then(|dr| dr.body().map(|body| (dr, body))) {
+            Some((dr, template)) => {
+                let status = status.to_string();
+                let vars = [
+                    ("status", status.as_str()),
+                    ("reason", self.reason()),
+                    ("request_id", request_id.unwrap_or_default()),
+                ];
+                render_template(template, &vars, dr.is_json()).into_bytes()
+            }
+            None => self.body().to_vec(),
+        };
+
+        Reply {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// Replies to the request of `ctx` with the rejection, stopping it from going upstream.
+    pub fn send<C: HttpContext + ?Sized>(
+        &self,
+        ctx: &C,
+        deny_response: Option<&DenyResponse>,
+        backend_failure_status: u32,
+    ) {
+        let request_id = ctx.get_http_request_header("x-request-id");
+        let reply = self.reply(deny_response, backend_failure_status, request_id.as_deref());
+        info!(
+            "threescale_wasm_auth: rejecting request with status {}: {}",
+            reply.status,
+            self.reason()
+        );
+        ctx.send_http_response(
+            reply.status,
+            reply
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+            Some(reply.body.as_slice()),
+        );
+    }
+
+    /// Classifies the answer of the 3scale backend to an authrep call, with its body parsed if any.
+    ///
+    /// Returns `None` when the request is authorized. The status is `None` when the call
+    /// failed without a response, ie. timed out or the connection was reset. `now` is the
+    /// current time in seconds since the UNIX epoch, used to compute the `Retry-After` value.
+    pub fn from_authrep_response(
+        status: Option<u32>,
+        response: Option<&Result<AuthRepResponse, ResponseError>>,
+        now: u64,
+    ) -> Option<Self> {
+        let status = match status {
+            Some(status) if status < 500 => status,
+            _ => return Some(Self::BackendUnavailable),
+        };
+
+        let response = match response {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => {
+                log::warn!(
+                    "could not parse backend response (status {}): {:?}",
+                    status,
+                    e
+                );
+                return Self::from_status(status);
+            }
+            None => {
+                log::warn!("empty backend response (status {})", status);
+                return Self::from_status(status);
+            }
+        };
+
+        log::debug!("backend response {:#?}", response);
+
+        match response {
+            AuthRepResponse::Status(st) if st.is_authorized() => None,
+            AuthRepResponse::Status(st) => {
+                let reason = st.reason().map(str::to_string);
+                if st.is_limited() {
+                    Some(Self::LimitsExceeded {
+                        reason,
+                        retry_after: st.retry_after(now),
+                    })
+                } else {
+                    Some(Self::Denied(reason))
+                }
+            }
+            AuthRepResponse::Error(e) => {
+                log::info!("backend error {}: {}", e.code(), e.message());
+                Some(Self::Denied(Some(e.message().to_string())))
+            }
+        }
+    }
+
+    // fallback for responses we could not make sense of
+    fn from_status(status: u32) -> Option<Self> {
+        if status == 200 {
+            None
+        } else {
+            Some(Self::Denied(None))
+        }
+    }
+}
+
+// Replaces every `{name}` occurrence for the given variables, leaving unknown ones as is.
+fn render_template(template: &str, vars: &[(&str, &str)], json: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let var = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| (end, *value))
+        });
+        match var {
+            Some((end, value)) => {
+                if json {
+                    let quoted = serde_json::Value::from(value).to_string();
+                    out.push_str(&quoted[1..quoted.len() - 1]);
+                } else {
+                    out.push_str(value);
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::TryFrom;
+
+    const NOW: u64 = 1_614_592_830;
+
+    fn from_backend_response(
+        status: Option<u32>,
+        body: Option<&[u8]>,
+        now: u64,
+    ) -> Option<Rejection> {
+        Rejection::from_authrep_response(status, body.map(AuthRepResponse::try_from).as_ref(), now)
+    }
+
+    fn limited(period: &str, period_end: &str) -> String {
+        format!(
+            r#"<status>
+  <authorized>false</authorized>
+  <reason>usage limits are exceeded</reason>
+  <usage_reports>
+    <usage_report metric="hits" period="{}" exceeded="true">
+      {}
+      <max_value>5</max_value>
+      <current_value>5</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#,
+            period, period_end
+        )
+    }
+
+    #[test]
+    fn it_computes_retry_after_for_limits_exceeded() {
+        let body = limited(
+            "minute",
+            "<period_start>2021-03-01 10:00:00 +0000</period_start>\
+             <period_end>2021-03-01 10:01:00 +0000</period_end>",
+        );
+        let rejection = from_backend_response(Some(409), Some(body.as_bytes()), NOW);
+        assert_eq!(
+            rejection,
+            Some(Rejection::LimitsExceeded {
+                reason: Some("usage limits are exceeded".into()),
+                retry_after: Some(30)
+            })
+        );
+        let rejection = rejection.unwrap();
+        assert_eq!(rejection.status(503), 429);
+        assert_eq!(rejection.headers(), vec![("retry-after", "30".to_string())]);
+    }
+
+    #[test]
+    fn it_omits_retry_after_for_eternity_limits() {
+        let body = limited("eternity", "");
+        let rejection = from_backend_response(Some(409), Some(body.as_bytes()), NOW);
+        assert!(matches!(
+            rejection,
+            Some(Rejection::LimitsExceeded {
+                retry_after: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn it_does_not_take_disabled_metrics_for_exceeded_limits() {
+        // disabled metrics have a max_value of 0
+        let body = br#"<status>
+  <authorized>false</authorized>
+  <reason>application key is invalid</reason>
+  <usage_reports>
+    <usage_report metric="hits" period="minute">
+      <period_start>2021-03-01 10:00:00 +0000</period_start>
+      <period_end>2021-03-01 10:01:00 +0000</period_end>
+      <max_value>0</max_value>
+      <current_value>0</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#;
+        let rejection = from_backend_response(Some(409), Some(body), NOW).unwrap();
+        assert_eq!(
+            rejection,
+            Rejection::Denied(Some("application key is invalid".into()))
+        );
+        assert_eq!(rejection.status(503), 403);
+        assert!(rejection.headers().is_empty());
+    }
+
+    #[test]
+    fn it_denies_invalid_credentials() {
+        let body = br#"<error code="user_key_invalid">user key "foo" is invalid</error>"#;
+        let rejection = from_backend_response(Some(403), Some(body), NOW).unwrap();
+        assert_eq!(rejection.status(503), 403);
+        assert_eq!(rejection.reason(), r#"user key "foo" is invalid"#);
+    }
+
+    #[test]
+    fn it_detects_backend_failures() {
+        assert_eq!(
+            from_backend_response(None, None, NOW),
+            Some(Rejection::BackendUnavailable)
+        );
+        let rejection = from_backend_response(Some(502), Some(b"bad gateway"), NOW);
+        assert_eq!(rejection, Some(Rejection::BackendUnavailable));
+        assert_eq!(rejection.unwrap().status(504), 504);
+    }
+
+    #[test]
+    fn it_authorizes() {
+        let body = b"<status><authorized>true</authorized></status>";
+        assert_eq!(from_backend_response(Some(200), Some(body), NOW), None);
+    }
+
+    #[test]
+    fn it_renders_configured_json_responses() {
+        let deny_response = DenyResponse {
+            status: Some(403),
+            headers: Some(vec![(
+                "Content-Type".into(),
+                "application/problem+json".into(),
+            )]),
+            body: Some(
+                r#"{"status": {status}, "error": "{reason}", "id": "{request_id}", "x": {}}"#
+                    .into(),
+            ),
+        };
+        let rejection = Rejection::Denied(Some(r#"user key "foo" is invalid"#.into()));
+        let reply = rejection.reply(Some(&deny_response), 503, Some("abc-123"));
+        assert_eq!(reply.status, 403);
+        assert_eq!(
+            String::from_utf8(reply.body).unwrap(),
+            r#"{"status": 403, "error": "user key \"foo\" is invalid", "id": "abc-123", "x": {}}"#
+        );
+    }
+
+    #[test]
+    fn it_fills_in_defaults() {
+        let rejection = Rejection::LimitsExceeded {
+            reason: None,
+            retry_after: Some(10),
+        };
+        let deny_response = DenyResponse {
+            status: None,
+            headers: None,
+            body: Some("limited: {reason} {unknown}".into()),
+        };
+        let reply = rejection.reply(Some(&deny_response), 503, None);
+        assert_eq!(reply.status, 429);
+        assert_eq!(
+            reply.headers,
+            vec![("retry-after".to_string(), "10".to_string())]
+        );
+        assert_eq!(reply.body, b"limited: usage limits are exceeded {unknown}");
+
+        let reply = Rejection::BackendUnavailable.reply(None, 502, None);
+        assert_eq!(reply.status, 502);
+        assert_eq!(reply.body, Rejection::BackendUnavailable.body());
+    }
+
+    #[test]
+    fn it_answers_unmatched_requests_as_not_found() {
+        let reply = Rejection::NoMappingRuleMatched.reply(None, 503, None);
+        assert_eq!(reply.status, 404);
+        assert_eq!(reply.body, b"No mapping rule matched.\n");
+    }
+
+    #[test]
+    fn it_answers_unavailable_when_not_configured() {
+        let responses = Responses {
+            backend_unavailable: Some(DenyResponse {
+                status: Some(500),
+                headers: None,
+                body: None,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(Rejection::NotConfigured.deny_response(&responses), None);
+        let reply = Rejection::NotConfigured.reply(None, 504, None);
+        assert_eq!(reply.status, 503);
+    }
+}
@@ -19,10 +19,11 @@ mod helpers {
         (path, v.next())
     }
 
+    // Cookies are separated by "; ", so names are trimmed.
     fn extract_cookies(cookie_value: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
         cookie_value.split(';').map(|kv| {
             let mut kviter = kv.splitn(2, '=');
-            (kviter.next().unwrap(), kviter.next())
+            (kviter.next().unwrap().trim(), kviter.next())
         })
     }
     pub fn get_cookie<'a>(cookie_value: &'a str, name: &str) -> Option<Option<&'a str>> {
@@ -85,11 +86,12 @@ impl RequestHeaders {
         self.0.iter_mut()
     }
 
+    // HTTP/2 allows cookies to be split into several headers, so all of them are looked into.
     pub fn get_cookie_from_header(&self, header: &str, name: &str) -> Option<Option<&str>> {
-        match self.get(header) {
-            Some(cookie_value) => helpers::get_cookie(cookie_value, name),
-            None => None,
-        }
+        self.0
+            .iter()
+            .filter(|(h, _)| h == header)
+            .find_map(|(_, cookie_value)| helpers::get_cookie(cookie_value, name))
     }
 
     pub fn path_n_qs(&self) -> (&str, Option<&str>) {
@@ -143,3 +145,35 @@ impl TryFrom<&RequestHeaders> for Url {
         rh.url()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_finds_cookies_in_every_cookie_header() {
+        let rh = RequestHeaders(vec![
+            ("cookie".to_string(), "theme=dark; lang".to_string()),
+            ("user-agent".to_string(), "curl".to_string()),
+            (
+                "cookie".to_string(),
+                "session=abc=; user_key=secret".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            rh.get_cookie_from_header("cookie", "theme"),
+            Some(Some("dark"))
+        );
+        assert_eq!(rh.get_cookie_from_header("cookie", "lang"), Some(None));
+        assert_eq!(
+            rh.get_cookie_from_header("cookie", "session"),
+            Some(Some("abc="))
+        );
+        assert_eq!(
+            rh.get_cookie_from_header("cookie", "user_key"),
+            Some(Some("secret"))
+        );
+        assert_eq!(rh.get_cookie_from_header("cookie", "user"), None);
+        assert_eq!(rh.get_cookie_from_header("user-agent", "curl"), Some(None));
+    }
+}
@@ -0,0 +1,395 @@
+// Access to the data shared by the filter VMs in the host, ie. one per Envoy worker thread.
+//
+// Each cached authorization lives under its own shared data key, updated with CAS retries,
+// so that only requests of the same application and metrics contend for it. Keys are made
+// of a hash of the application, so that its credentials do not show in shared data. The
+// host cannot delete keys, so removed entries are left empty, and keys are never stored
+// for entries that do not exist so that unknown applications leave no trace.
+//
+// Usage to be reported goes through a shared queue instead, so that each item is dequeued,
+// and therefore reported, by exactly one VM.
+use core::convert::TryInto;
+use core::hash::{Hash, Hasher};
+use log::{error, warn};
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::Status;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+
+use super::cache::{CacheIndex, CacheKey, Entry};
+use super::state::{AppRef, Report};
+
+const CACHE_PREFIX: &str = "threescale_wasm_auth.cache.";
+const CACHE_INDEX_KEY: &str = "threescale_wasm_auth.cache_index";
+const AUTHORIZED_PREFIX: &str = "threescale_wasm_auth.authorized.";
+const LAST_FLUSH_KEY: &str = "threescale_wasm_auth.last_flush";
+const REPORTS_QUEUE: &str = "threescale_wasm_auth.reports";
+// attempts at updating shared data before giving up due to contention
+const MAX_CAS_RETRIES: usize = 8;
+
+// Builds the key of the data kept for `value` out of two hashes of it, which are the same
+// in all the VMs and make collisions negligible.
+fn hashed_key(prefix: &str, value: &impl Hash) -> String {
+    let hash = |seed: u8| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        value.hash(&mut hasher);
+        hasher.finish()
+    };
+
+    format!("{}{:016x}{:016x}", prefix, hash(0), hash(1))
+}
+
+// Empty or invalid data is taken as missing.
+fn decode<T: DeserializeOwned>(key: &str, data: Option<&[u8]>) -> Option<T> {
+    data.filter(|data| !data.is_empty()).and_then(|data| {
+        serde_json::from_slice(data)
+            .map_err(|e| warn!("discarding invalid shared data {}: {}", key, e))
+            .ok()
+    })
+}
+
+// Missing values are encoded as empty data.
+fn encode<T: Serialize>(key: &str, value: Option<&T>) -> Result<Vec<u8>, Status> {
+    match value.map(serde_json::to_vec).transpose() {
+        Ok(data) => Ok(data.unwrap_or_default()),
+        Err(e) => {
+            error!("could not serialize shared data {}: {}", key, e);
+            Err(Status::InternalFailure)
+        }
+    }
+}
+
+fn get<T: DeserializeOwned>(key: &str) -> Result<(Option<T>, Option<u32>), Status> {
+    let (data, cas) = hostcalls::get_shared_data(key)?;
+    Ok((decode(key, data.as_deref()), cas))
+}
+
+// Stores the value under `key` regardless of its current one.
+fn store<T: Serialize>(key: &str, value: &T) -> bool {
+    encode(key, Some(value))
+        .and_then(|data| hostcalls::set_shared_data(key, Some(data.as_slice()), None))
+        .map_err(|e| error!("could not store shared data {}: {:?}", key, e))
+        .is_ok()
+}
+
+// Clears the value under `key`, leaving keys that were never stored or are cleared already
+// alone, since the host would keep any key stored for good.
+fn clear(key: &str) -> bool {
+    let result = match hostcalls::get_shared_data(key) {
+        Ok((Some(data), cas)) if !data.is_empty() => {
+            hostcalls::set_shared_data(key, Some(&[]), cas)
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    result
+        .map_err(|e| error!("could not clear shared data {}: {:?}", key, e))
+        .is_ok()
+}
+
+// Updates the value under `key` with `f`, which is called again with the fresh value
+// whenever another VM updated it in the meantime, and which can clear it by leaving `None`.
+// Values left as they were are not stored again, so that no key is stored for a value that
+// was and is still missing. Returns `None` if the value could not be updated.
+fn update<T, R>(key: &str, mut f: impl FnMut(&mut Option<T>) -> R) -> Option<R>
+where
+    T: Serialize + DeserializeOwned,
+{
+    for _ in 0..MAX_CAS_RETRIES {
+        let (data, cas) = match hostcalls::get_shared_data(key) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("could not retrieve shared data {}: {:?}", key, e);
+                return None;
+            }
+        };
+        let data = data.unwrap_or_default();
+        let mut value = decode(key, Some(data.as_slice()));
+        let result = f(&mut value);
+
+        let updated = encode(key, value.as_ref()).ok()?;
+        if updated == data {
+            return Some(result);
+        }
+        match hostcalls::set_shared_data(key, Some(updated.as_slice()), cas) {
+            Ok(()) => return Some(result),
+            Err(Status::CasMismatch) => continue,
+            Err(e) => {
+                error!("could not store shared data {}: {:?}", key, e);
+                return None;
+            }
+        }
+    }
+
+    warn!(
+        "could not update shared data {} after {} attempts",
+        key, MAX_CAS_RETRIES
+    );
+    None
+}
+
+/// Authorizes the usage from the cached authorization, if any allows it.
+pub(crate) fn cache_authorize(key: &CacheKey, usages: &HashMap<String, i64>, now: u64) -> bool {
+    update(
+        hashed_key(CACHE_PREFIX, key).as_str(),
+        |entry: &mut Option<Entry>| {
+            entry
+                .as_mut()
+                .map(|entry| entry.authorize(usages, now))
+                .unwrap_or(false)
+        },
+    )
+    .unwrap_or(false)
+}
+
+/// Caches an authorization, evicting others if there are already `max_entries`.
+pub(crate) fn cache_insert(key: &CacheKey, entry: &Entry, now: u64, max_entries: usize) -> bool {
+    if max_entries == 0 {
+        return true;
+    }
+
+    let key = hashed_key(CACHE_PREFIX, key);
+    let evicted = update(CACHE_INDEX_KEY, |index: &mut Option<CacheIndex>| {
+        index.get_or_insert_with(CacheIndex::default).insert(
+            key.clone(),
+            entry.expires_at(),
+            now,
+            max_entries,
+        )
+    });
+    for evicted in evicted.iter().flatten() {
+        clear(evicted);
+    }
+
+    evicted.is_some() && store(key.as_str(), entry)
+}
+
+pub(crate) fn cache_remove(key: &CacheKey) -> bool {
+    let key = hashed_key(CACHE_PREFIX, key);
+    let removed = clear(key.as_str());
+    // the index is left as it was, and so not stored, unless it had the entry
+    let unindexed = update(CACHE_INDEX_KEY, |index: &mut Option<CacheIndex>| {
+        if let Some(index) = index.as_mut() {
+            index.remove(key.as_str());
+        }
+    });
+
+    removed && unindexed.is_some()
+}
+
+/// Clears the cached authorizations that expired. Returns how many are left.
+pub(crate) fn cache_expire(now: u64) -> Option<usize> {
+    let (expired, left) = update(CACHE_INDEX_KEY, |index: &mut Option<CacheIndex>| {
+        index
+            .as_mut()
+            .map(|index| (index.expire(now), index.len()))
+            .unwrap_or_default()
+    })?;
+    for key in expired.iter() {
+        clear(key);
+    }
+
+    Some(left)
+}
+
+/// Records the last time, in seconds since the UNIX epoch, the backend authorized `app`.
+pub(crate) fn record_authorized(app: &AppRef, now: u64) -> bool {
+    store(hashed_key(AUTHORIZED_PREFIX, app).as_str(), &now)
+}
+
+pub(crate) fn forget_authorized(app: &AppRef) -> bool {
+    clear(hashed_key(AUTHORIZED_PREFIX, app).as_str())
+}
+
+/// Whether the backend authorized `app` in the last `ttl` seconds.
+pub(crate) fn recently_authorized(app: &AppRef, now: u64, ttl: u64) -> bool {
+    let key = hashed_key(AUTHORIZED_PREFIX, app);
+    match get::<u64>(key.as_str()) {
+        Ok((last, _)) => last
+            .map(|last| now.saturating_sub(last) < ttl)
+            .unwrap_or(false),
+        Err(e) => {
+            error!("could not retrieve shared data {}: {:?}", key, e);
+            false
+        }
+    }
+}
+
+/// Claims the periodic flush of pending usage for this VM if no other VM flushed in the
+/// last `interval` seconds.
+pub(crate) fn claim_flush(now: u64, interval: u64) -> bool {
+    let (data, cas) = match hostcalls::get_shared_data(LAST_FLUSH_KEY) {
+        Ok(last_flush) => last_flush,
+        Err(e) => {
+            error!("could not retrieve last flush time: {:?}", e);
+            return false;
+        }
+    };
+    let last_flush = data
+        .and_then(|data| data.as_slice().try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0);
+    if now.saturating_sub(last_flush) < interval {
+        return false;
+    }
+
+    // a CAS mismatch means another VM just claimed it
+    hostcalls::set_shared_data(LAST_FLUSH_KEY, Some(&now.to_le_bytes()), cas).is_ok()
+}
+
+/// Registers the reports queue, making this VM the one notified of new items.
+pub(crate) fn register_reports_queue() -> Option<u32> {
+    hostcalls::register_shared_queue(REPORTS_QUEUE)
+        .map_err(|e| error!("could not register shared queue {}: {:?}", REPORTS_QUEUE, e))
+        .ok()
+}
+
+pub(crate) fn enqueue_report(queue_id: u32, report: &Report) -> bool {
+    let data = match serde_json::to_vec(report) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("could not serialize report: {}", e);
+            return false;
+        }
+    };
+
+    hostcalls::enqueue_shared_queue(queue_id, Some(data.as_slice()))
+        .map_err(|e| error!("could not enqueue report: {:?}", e))
+        .is_ok()
+}
+
+/// Dequeues the next valid report, if any.
+pub(crate) fn dequeue_report(queue_id: u32) -> Option<Report> {
+    loop {
+        match hostcalls::dequeue_shared_queue(queue_id) {
+            Ok(Some(data)) => match serde_json::from_slice(data.as_slice()) {
+                Ok(report) => return Some(report),
+                Err(e) => warn!("discarding invalid queued report: {}", e),
+            },
+            Ok(None) | Err(Status::Empty) => return None,
+            Err(e) => {
+                error!("could not dequeue report: {:?}", e);
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::ApplicationKind;
+    use crate::proxy::test_host::TestHost;
+
+    const NOW: u64 = 1_614_592_830;
+
+    fn app(app_id: &str, app_key: Option<&str>) -> AppRef {
+        AppRef {
+            service_id: "2555417834780".into(),
+            kind: ApplicationKind::AppId,
+            app_id: app_id.into(),
+            app_key: app_key.map(Into::into),
+        }
+    }
+
+    fn hits() -> HashMap<String, i64> {
+        std::iter::once(("hits".to_string(), 1)).collect()
+    }
+
+    #[test]
+    fn it_caches_authorizations_under_their_own_keys() {
+        let host = TestHost::new();
+        let foo = CacheKey::new(&app("foo", Some("foo-secret")), &hits());
+        let bar = CacheKey::new(&app("bar", Some("bar-secret")), &hits());
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+
+        assert!(cache_insert(&foo, &Entry::new(&[], NOW, 10), NOW, 2));
+        assert!(cache_insert(&bar, &Entry::new(&[], NOW, 20), NOW, 2));
+        assert!(cache_authorize(&foo, &hits(), NOW));
+        assert!(cache_authorize(&bar, &hits(), NOW));
+
+        let shared_data = host.shared_data();
+        assert_eq!(shared_data.len(), 3);
+        for (key, value) in shared_data {
+            let value = String::from_utf8(value).unwrap();
+            assert!(!key.contains("secret") && !value.contains("secret"));
+        }
+
+        assert_eq!(cache_expire(NOW + 10), Some(1));
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+        assert!(cache_remove(&bar));
+        assert!(!cache_authorize(&bar, &hits(), NOW));
+        assert_eq!(cache_expire(NOW + 10), Some(0));
+        assert_eq!(host.shared_data().len(), 1);
+    }
+
+    #[test]
+    fn it_evicts_cached_authorizations_over_the_cap() {
+        let _host = TestHost::new();
+        let foo = CacheKey::new(&app("foo", None), &hits());
+        let bar = CacheKey::new(&app("bar", None), &hits());
+
+        assert!(cache_insert(&foo, &Entry::new(&[], NOW, 10), NOW, 1));
+        assert!(cache_insert(&bar, &Entry::new(&[], NOW, 10), NOW, 1));
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+        assert!(cache_authorize(&bar, &hits(), NOW));
+
+        assert!(cache_insert(&foo, &Entry::new(&[], NOW, 10), NOW, 0));
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+    }
+
+    #[test]
+    fn it_stores_no_keys_for_unknown_applications() {
+        let host = TestHost::new();
+        let foo = CacheKey::new(&app("foo", None), &hits());
+        let bar = CacheKey::new(&app("bar", None), &hits());
+
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+        assert!(cache_remove(&foo));
+        assert!(forget_authorized(&app("foo", None)));
+        assert!(!recently_authorized(&app("foo", None), NOW, 60));
+        assert_eq!(host.shared_data_keys(), 0);
+
+        // the cache entry and the index
+        assert!(cache_insert(&bar, &Entry::new(&[], NOW, 10), NOW, 2));
+        assert_eq!(host.shared_data_keys(), 2);
+        let index = hostcalls::get_shared_data(CACHE_INDEX_KEY).unwrap();
+        assert!(!cache_authorize(&foo, &hits(), NOW));
+        assert!(cache_remove(&foo));
+        assert!(forget_authorized(&app("foo", None)));
+        assert_eq!(host.shared_data_keys(), 2);
+        assert_eq!(hostcalls::get_shared_data(CACHE_INDEX_KEY).unwrap(), index);
+
+        // cleared keys stay cleared
+        assert!(cache_remove(&bar));
+        assert_ne!(hostcalls::get_shared_data(CACHE_INDEX_KEY).unwrap(), index);
+        assert!(!cache_authorize(&bar, &hits(), NOW));
+        assert!(cache_remove(&bar));
+        assert_eq!(host.shared_data_keys(), 2);
+        let shared_data = host.shared_data();
+        assert_eq!(
+            shared_data.keys().collect::<Vec<_>>(),
+            vec![CACHE_INDEX_KEY]
+        );
+    }
+
+    #[test]
+    fn it_remembers_recent_authorizations() {
+        let host = TestHost::new();
+        let app = app("foo", Some("foo-secret"));
+        assert!(!recently_authorized(&app, 100, 60));
+
+        assert!(record_authorized(&app, 100));
+        assert!(recently_authorized(&app, 159, 60));
+        assert!(!recently_authorized(&app, 160, 60));
+        assert!(host.shared_data().keys().all(|key| !key.contains("foo")));
+
+        assert!(record_authorized(&app, 200));
+        assert!(forget_authorized(&app));
+        assert!(!recently_authorized(&app, 200, 60));
+    }
+}
@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::configuration::ApplicationKind;
+
+/// Default cap on the number of applications with usage waiting to be reported.
+pub(crate) const MAX_PENDING_APPS: usize = 10_000;
+
+/// An application of a given service, as identified by its credentials.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct AppRef {
+    pub service_id: String,
+    pub kind: ApplicationKind,
+    pub app_id: String,
+    pub app_key: Option<String>,
+}
+
+/// Usage of an application, by metric.
+pub(crate) type Report = (AppRef, HashMap<String, i64>);
+
+/// Usage not yet reported to the backend, aggregated per application and metric.
+#[derive(Debug)]
+pub(crate) struct PendingUsage {
+    pending: HashMap<AppRef, HashMap<String, i64>>,
+    max_apps: usize,
+}
+
+impl PendingUsage {
+    pub fn new(max_apps: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            max_apps,
+        }
+    }
+
+    /// Adds usage to be reported. Returns `false` if there are too many applications already.
+    pub fn add(&mut self, app: &AppRef, usages: &HashMap<String, i64>) -> bool {
+        if !self.pending.contains_key(app) && self.pending.len() >= self.max_apps {
+            return false;
+        }
+
+        let pending = self.pending.entry(app.clone()).or_default();
+        for (metric, delta) in usages {
+            *pending.entry(metric.clone()).or_insert(0) += delta;
+        }
+
+        true
+    }
+
+    /// Takes all the pending usage, grouped by service id.
+    pub fn take(&mut self) -> HashMap<String, Vec<Report>> {
+        let mut by_service = HashMap::<_, Vec<_>>::new();
+        for (app, usages) in self.pending.drain() {
+            by_service
+                .entry(app.service_id.clone())
+                .or_default()
+                .push((app, usages));
+        }
+
+        by_service
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn app(app_id: &str) -> AppRef {
+        AppRef {
+            service_id: "2555417834780".into(),
+            kind: ApplicationKind::UserKey,
+            app_id: app_id.into(),
+            app_key: None,
+        }
+    }
+
+    #[test]
+    fn it_aggregates_pending_usage() {
+        let mut pending = PendingUsage::new(2);
+        let usages = vec![("hits".to_string(), 1), ("bytes".to_string(), 10)]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        assert!(pending.add(&app("foo"), &usages));
+        assert!(pending.add(&app("foo"), &usages));
+        assert!(pending.add(&app("bar"), &usages));
+        assert!(!pending.add(&app("baz"), &usages));
+        assert!(pending.add(&app("foo"), &usages));
+
+        let by_service = pending.take();
+        assert!(pending.take().is_empty());
+        let reports = by_service.get("2555417834780").unwrap();
+        assert_eq!(reports.len(), 2);
+        let (_, foo_usages) = reports.iter().find(|(app, _)| app.app_id == "foo").unwrap();
+        assert_eq!(foo_usages.get("hits"), Some(&3));
+        assert_eq!(foo_usages.get("bytes"), Some(&30));
+    }
+}
@@ -0,0 +1,232 @@
+// Calls to the 3scale System admin API for the configuration of services, and the
+// configurations obtained from it.
+use proxy_wasm::traits::Context;
+use std::collections::HashMap;
+
+use crate::configuration::{Configuration, ProxyConfig, Service, System};
+
+// Percent-encodes all but the unreserved characters of RFC 3986, so that the value is taken
+// as a single path segment.
+fn path_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn proxy_config_path(system: &System, service: &Service) -> String {
+    format!(
+        "admin/api/services/{}/proxy/configs/{}/latest.json",
+        path_segment(service.id()),
+        path_segment(system.environment())
+    )
+}
+
+// System takes the access token as the user name of basic authentication, which keeps it
+// out of the path of the call.
+fn authorization(system: &System) -> String {
+    format!("Basic {}", base64::encode(format!("{}:", system.token())))
+}
+
+/// Asks System for the latest configuration of a service, returning the call token.
+///
+/// When the ETag of the configuration already obtained is given, System answers with
+/// a 304 status if it has not changed since.
+pub(crate) fn fetch_proxy_config<C: Context>(
+    ctx: &C,
+    system: &System,
+    service: &Service,
+    etag: Option<&str>,
+) -> Result<u32, anyhow::Error> {
+    let path = proxy_config_path(system, service);
+    let authorization = authorization(system);
+    let mut headers = vec![
+        ("accept", "application/json"),
+        ("authorization", authorization.as_str()),
+    ];
+    if let Some(etag) = etag {
+        headers.push(("if-none-match", etag));
+    }
+
+    system
+        .upstream()
+        .call(ctx, path.as_str(), "GET", headers, None, None, None)
+}
+
+#[derive(Debug)]
+struct Fetched {
+    proxy_config: ProxyConfig,
+    etag: Option<String>,
+}
+
+/// The last good configuration of each service obtained from System, and the fetches of
+/// newer ones in progress.
+#[derive(Debug, Default)]
+pub(crate) struct ProxyConfigs {
+    fetched: HashMap<String, Fetched>,
+    // service ids whose configuration is being fetched, by call token
+    calls: HashMap<u32, String>,
+    // seconds since the UNIX epoch of the last round of fetches
+    last_fetch: Option<u64>,
+}
+
+impl ProxyConfigs {
+    /// Checks whether another round of fetches is due after `interval` seconds, with 0
+    /// meaning configurations are only fetched once.
+    pub fn fetch_due(&self, now: u64, interval: u64) -> bool {
+        match self.last_fetch {
+            None => true,
+            Some(_) if interval == 0 => false,
+            Some(last_fetch) => now.saturating_sub(last_fetch) >= interval,
+        }
+    }
+
+    pub fn fetching(&self, service_id: &str) -> bool {
+        self.calls.values().any(|id| id == service_id)
+    }
+
+    pub fn etag(&self, service_id: &str) -> Option<&str> {
+        self.fetched
+            .get(service_id)
+            .and_then(|fetched| fetched.etag.as_deref())
+    }
+
+    pub fn fetched_at(&mut self, now: u64) {
+        self.last_fetch = Some(now);
+    }
+
+    pub fn add_call(&mut self, call_token: u32, service_id: &str) {
+        self.calls.insert(call_token, service_id.to_string());
+    }
+
+    /// Returns the id of the service whose configuration was fetched with the call, if any.
+    pub fn take_call(&mut self, call_token: u32) -> Option<String> {
+        self.calls.remove(&call_token)
+    }
+
+    /// Keeps a configuration fetched for a service unless it is not newer than the one
+    /// kept already, returning whether it was kept.
+    pub fn update(
+        &mut self,
+        service_id: &str,
+        proxy_config: ProxyConfig,
+        etag: Option<String>,
+    ) -> bool {
+        if let Some(fetched) = self.fetched.get(service_id) {
+            if fetched.proxy_config.version() >= proxy_config.version() {
+                return false;
+            }
+        }
+
+        self.fetched
+            .insert(service_id.to_string(), Fetched { proxy_config, etag });
+        true
+    }
+
+    /// Builds the configuration to use from the plugin configuration and the service
+    /// configurations fetched so far.
+    pub fn apply(&self, plugin_configuration: &Configuration) -> Configuration {
+        let mut configuration = plugin_configuration.clone();
+        for (service_id, fetched) in self.fetched.iter() {
+            if let Some(service) = configuration.get_service_mut(service_id) {
+                service.merge_proxy_config(&fetched.proxy_config);
+            }
+        }
+
+        configuration
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn proxy_config(version: u64, pattern: &str) -> ProxyConfig {
+        let body = format!(
+            r#"{{ "proxy_config": {{ "version": {}, "environment": "production", "content": {{
+                "backend_version": "1",
+                "proxy": {{ "proxy_rules": [
+                  {{ "http_method": "GET", "pattern": "{}", "metric_system_name": "hits", "delta": 1 }}
+                ] }}
+            }} }} }}"#,
+            version, pattern
+        );
+        ProxyConfig::from_response(body.as_bytes()).unwrap()
+    }
+
+    fn plugin_configuration() -> Configuration {
+        serde_json::from_str(
+            r#"{ "services": [{ "id": "2555417834780", "token": "service_token" }] }"#,
+        )
+        .unwrap()
+    }
+
+    fn pattern(configuration: &Configuration) -> &str {
+        configuration
+            .get_service("2555417834780")
+            .unwrap()
+            .mapping_rules()[0]
+            .pattern()
+    }
+
+    #[test]
+    fn it_keeps_values_and_tokens_out_of_the_path() {
+        let configuration: Configuration = serde_json::from_str(
+            r#"{ "system": { "upstream": { "name": "system", "url": "https://system", "timeout": 5000 },
+                             "token": "my token", "environment": "staging/eu" },
+                 "services": [{ "id": "../2555417834780", "token": "service_token" }] }"#,
+        )
+        .unwrap();
+        let system = configuration.system().unwrap();
+        let service = configuration.get_service("../2555417834780").unwrap();
+
+        assert_eq!(
+            proxy_config_path(system, service),
+            "admin/api/services/..%2F2555417834780/proxy/configs/staging%2Feu/latest.json"
+        );
+        assert_eq!(authorization(system), "Basic bXkgdG9rZW46");
+    }
+
+    #[test]
+    fn it_keeps_the_newest_configurations() {
+        let mut configs = ProxyConfigs::default();
+        let plugin_configuration = plugin_configuration();
+
+        assert!(configs.update(
+            "2555417834780",
+            proxy_config(2, "/v2"),
+            Some("\"2\"".into())
+        ));
+        assert!(!configs.update("2555417834780", proxy_config(1, "/v1"), None));
+        assert_eq!(configs.etag("2555417834780"), Some("\"2\""));
+        assert_eq!(pattern(&configs.apply(&plugin_configuration)), "/v2");
+
+        assert!(configs.update("2555417834780", proxy_config(3, "/v3"), None));
+        assert_eq!(pattern(&configs.apply(&plugin_configuration)), "/v3");
+        assert!(plugin_configuration
+            .get_service("2555417834780")
+            .unwrap()
+            .mapping_rules()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_schedules_fetches() {
+        let mut configs = ProxyConfigs::default();
+        assert!(configs.fetch_due(100, 0));
+        configs.fetched_at(100);
+        assert!(!configs.fetch_due(1000, 0));
+        assert!(!configs.fetch_due(159, 60));
+        assert!(configs.fetch_due(160, 60));
+
+        configs.add_call(7, "2555417834780");
+        assert!(configs.fetching("2555417834780"));
+        assert_eq!(configs.take_call(7).as_deref(), Some("2555417834780"));
+        assert!(!configs.fetching("2555417834780"));
+    }
+}
@@ -0,0 +1,667 @@
+// In-process mock of the 3scale Service Management API for native tests.
+//
+// It answers the authrep, authorize and report calls dispatched to the test host, holding
+// the services, applications, limits and usage set up by tests, and answers them with the
+// same statuses and XML bodies as the 3scale backend.
+use std::collections::HashMap;
+
+use proxy_wasm::traits::Context;
+
+use super::authrep::response::Period;
+use super::test_host::{HttpCall, TestHost};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+#[derive(Debug)]
+enum AppCredentials {
+    UserKey(String),
+    AppId {
+        app_id: String,
+        app_keys: Vec<String>,
+    },
+}
+
+#[derive(Debug)]
+struct Limit {
+    metric: String,
+    period: Period,
+    max_value: u64,
+    current_value: u64,
+    // start of the period the current value is for
+    period_start: Option<u64>,
+}
+
+impl Limit {
+    // Resets the current value when a new period started.
+    fn refresh(&mut self, now: u64) {
+        let start = period_bounds(self.period, now).map(|(start, _)| start);
+        if start != self.period_start {
+            self.period_start = start;
+            self.current_value = 0;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockApp {
+    credentials: AppCredentials,
+    limits: Vec<Limit>,
+    // all usage reported, by metric
+    usage: HashMap<String, i64>,
+}
+
+impl MockApp {
+    fn is(&self, id: &str) -> bool {
+        match &self.credentials {
+            AppCredentials::UserKey(user_key) => user_key == id,
+            AppCredentials::AppId { app_id, .. } => app_id == id,
+        }
+    }
+
+    fn add_usage(&mut self, usage: &[(String, i64)]) {
+        for (metric, delta) in usage {
+            *self.usage.entry(metric.clone()).or_default() += delta;
+            for limit in self
+                .limits
+                .iter_mut()
+                .filter(|limit| limit.metric == *metric)
+            {
+                limit.current_value = (limit.current_value as i64 + delta).max(0) as u64;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockService {
+    token: String,
+    apps: Vec<MockApp>,
+}
+
+/// The 3scale backend as seen by the filter, answering the calls dispatched to the test host.
+///
+/// Applications are referred to by their user key or application id.
+#[derive(Debug, Default)]
+pub(crate) struct MockBackend {
+    services: HashMap<String, MockService>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_service(&mut self, service_id: &str, token: &str) -> &mut Self {
+        self.services.insert(
+            service_id.to_string(),
+            MockService {
+                token: token.to_string(),
+                apps: vec![],
+            },
+        );
+        self
+    }
+
+    fn add_app(&mut self, service_id: &str, credentials: AppCredentials) -> &mut Self {
+        self.service_mut(service_id).apps.push(MockApp {
+            credentials,
+            limits: vec![],
+            usage: HashMap::new(),
+        });
+        self
+    }
+
+    pub fn add_user_key(&mut self, service_id: &str, user_key: &str) -> &mut Self {
+        self.add_app(service_id, AppCredentials::UserKey(user_key.to_string()))
+    }
+
+    /// Adds an application identified by its id, which requires one of its keys if any.
+    pub fn add_app_id(&mut self, service_id: &str, app_id: &str, app_keys: &[&str]) -> &mut Self {
+        let app_keys = app_keys.iter().map(|key| key.to_string()).collect();
+        self.add_app(
+            service_id,
+            AppCredentials::AppId {
+                app_id: app_id.to_string(),
+                app_keys,
+            },
+        )
+    }
+
+    pub fn add_limit(
+        &mut self,
+        service_id: &str,
+        app: &str,
+        metric: &str,
+        period: Period,
+        max_value: u64,
+    ) -> &mut Self {
+        self.app_mut(service_id, app).limits.push(Limit {
+            metric: metric.to_string(),
+            period,
+            max_value,
+            current_value: 0,
+            period_start: None,
+        });
+        self
+    }
+
+    /// All the usage of a metric reported for an application.
+    pub fn usage(&self, service_id: &str, app: &str, metric: &str) -> i64 {
+        self.services
+            .get(service_id)
+            .and_then(|service| service.apps.iter().find(|mock_app| mock_app.is(app)))
+            .and_then(|mock_app| mock_app.usage.get(metric).copied())
+            .unwrap_or_default()
+    }
+
+    fn service_mut(&mut self, service_id: &str) -> &mut MockService {
+        self.services
+            .get_mut(service_id)
+            .unwrap_or_else(|| panic!("unknown mock service {}", service_id))
+    }
+
+    fn app_mut(&mut self, service_id: &str, app: &str) -> &mut MockApp {
+        self.service_mut(service_id)
+            .apps
+            .iter_mut()
+            .find(|mock_app| mock_app.is(app))
+            .unwrap_or_else(|| panic!("unknown mock application {}", app))
+    }
+
+    /// Answers all the calls dispatched to the host so far, delivering the responses to the
+    /// context that dispatched them, and returns how many there were.
+    pub fn serve<C: Context + ?Sized>(&mut self, host: &TestHost, ctx: &mut C) -> usize {
+        let calls = host.take_calls();
+        for call in calls.iter() {
+            let (status, body) = self.handle(call, host.now());
+            host.respond(ctx, call.token, status, &[], body.as_bytes());
+        }
+        calls.len()
+    }
+
+    /// Returns the status and body the backend answers a call with at `now`, in seconds
+    /// since the UNIX epoch.
+    pub fn handle(&mut self, call: &HttpCall, now: u64) -> (u32, String) {
+        let method = call.header(":method").unwrap_or_default();
+        let full_path = call.header(":path").unwrap_or_default();
+        let (path, query) = match full_path.find('?') {
+            Some(pos) => (&full_path[..pos], Some(&full_path[pos + 1..])),
+            None => (full_path, None),
+        };
+        let encoded = match (method, query, call.body.as_deref()) {
+            ("GET", Some(query), _) => query.as_bytes(),
+            ("POST", _, Some(body)) => body,
+            _ => &[],
+        };
+        let params = url::form_urlencoded::parse(encoded)
+            .into_owned()
+            .collect::<Vec<_>>();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let service = match self.authenticate(param("service_id"), param("service_token")) {
+            Ok(service) => service,
+            Err(error) => return error,
+        };
+
+        match (method, path) {
+            ("GET", "/transactions/authrep.xml") => authorize(service, &params, now, true),
+            ("GET", "/transactions/authorize.xml") => authorize(service, &params, now, false),
+            ("POST", "/transactions.xml") => report(service, &params, now),
+            _ => (404, String::new()),
+        }
+    }
+
+    fn authenticate(
+        &mut self,
+        service_id: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<&mut MockService, (u32, String)> {
+        let service_id = service_id.unwrap_or_default();
+        let service = self.services.get_mut(service_id).ok_or_else(|| {
+            let message = format!("service id \"{}\" is invalid", service_id);
+            (404, error_xml("service_id_invalid", message.as_str()))
+        })?;
+        let token = token.unwrap_or_default();
+        if service.token != token {
+            let message = format!("service token \"{}\" is invalid", token);
+            return Err((403, error_xml("service_token_invalid", message.as_str())));
+        }
+
+        Ok(service)
+    }
+}
+
+// Parses the usage in parameters such as `usage[hits]=1`, with `prefix` being `usage[`.
+fn usage_params(params: &[(String, String)], prefix: &str) -> Vec<(String, i64)> {
+    params
+        .iter()
+        .filter_map(|(key, value)| {
+            let metric = key.strip_prefix(prefix)?.strip_suffix(']')?;
+            Some((metric.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+fn find_app<'a>(
+    service: &'a mut MockService,
+    user_key: Option<&str>,
+    app_id: Option<&str>,
+) -> Result<&'a mut MockApp, (u32, String)> {
+    let found = match (user_key, app_id) {
+        (Some(user_key), _) => service.apps.iter_mut().find(|app| {
+            matches!(&app.credentials, AppCredentials::UserKey(key) if key == user_key)
+        }),
+        (None, Some(app_id)) => service.apps.iter_mut().find(|app| {
+            matches!(&app.credentials, AppCredentials::AppId { app_id: id, .. } if id == app_id)
+        }),
+        (None, None) => {
+            let body = error_xml("required_params_missing", "required params are missing");
+            return Err((422, body));
+        }
+    };
+
+    found.ok_or_else(|| match user_key {
+        Some(user_key) => {
+            let message = format!("user key \"{}\" is invalid", user_key);
+            (403, error_xml("user_key_invalid", message.as_str()))
+        }
+        None => {
+            let message = format!(
+                "application with id=\"{}\" was not found",
+                app_id.unwrap_or_default()
+            );
+            (404, error_xml("application_not_found", message.as_str()))
+        }
+    })
+}
+
+fn authorize(
+    service: &mut MockService,
+    params: &[(String, String)],
+    now: u64,
+    report: bool,
+) -> (u32, String) {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let app = match find_app(service, param("user_key"), param("app_id")) {
+        Ok(app) => app,
+        Err(error) => return error,
+    };
+    for limit in app.limits.iter_mut() {
+        limit.refresh(now);
+    }
+
+    if let AppCredentials::AppId { app_keys, .. } = &app.credentials {
+        let reason = match param("app_key") {
+            _ if app_keys.is_empty() => None,
+            Some(app_key) if app_keys.iter().any(|key| key == app_key) => None,
+            Some(app_key) => Some(format!("application key \"{}\" is invalid", app_key)),
+            None => Some("application key is missing".to_string()),
+        };
+        if let Some(reason) = reason {
+            return (409, status_xml(app, Some(reason.as_str()), &[], now));
+        }
+    }
+
+    let usage = usage_params(params, "usage[");
+    let exceeded = app
+        .limits
+        .iter()
+        .filter(|limit| {
+            usage.iter().any(|(metric, delta)| {
+                limit.metric == *metric
+                    && limit.current_value as i64 + delta > limit.max_value as i64
+            })
+        })
+        .map(|limit| (limit.metric.clone(), limit.period))
+        .collect::<Vec<_>>();
+    if !exceeded.is_empty() {
+        let body = status_xml(app, Some("usage limits are exceeded"), &exceeded, now);
+        return (409, body);
+    }
+
+    if report {
+        app.add_usage(usage.as_slice());
+    }
+    (200, status_xml(app, None, &[], now))
+}
+
+// Usage is accepted as long as the service is, dropping that of unknown applications.
+fn report(service: &mut MockService, params: &[(String, String)], now: u64) -> (u32, String) {
+    for n in 0.. {
+        let prefix = format!("transactions[{}]", n);
+        let param = |name: &str| {
+            let key = format!("{}[{}]", prefix, name);
+            params
+                .iter()
+                .find(|(param, _)| *param == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let (user_key, app_id) = (param("user_key"), param("app_id"));
+        if user_key.is_none() && app_id.is_none() {
+            break;
+        }
+
+        let usage = usage_params(params, format!("{}[usage][", prefix).as_str());
+        if let Ok(app) = find_app(service, user_key, app_id) {
+            for limit in app.limits.iter_mut() {
+                limit.refresh(now);
+            }
+            app.add_usage(usage.as_slice());
+        }
+    }
+
+    (202, String::new())
+}
+
+fn error_xml(code: &str, message: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><error code=\"{}\">{}</error>",
+        code,
+        escape(message)
+    )
+}
+
+fn status_xml(
+    app: &MockApp,
+    reason: Option<&str>,
+    exceeded: &[(String, Period)],
+    now: u64,
+) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><status>");
+    xml.push_str(&format!("<authorized>{}</authorized>", reason.is_none()));
+    if let Some(reason) = reason {
+        xml.push_str(&format!("<reason>{}</reason>", escape(reason)));
+    }
+    xml.push_str("<plan>Basic</plan>");
+
+    if !app.limits.is_empty() {
+        xml.push_str("<usage_reports>");
+        for limit in app.limits.iter() {
+            let is_exceeded = exceeded.contains(&(limit.metric.clone(), limit.period));
+            xml.push_str(&format!(
+                "<usage_report metric=\"{}\" period=\"{}\"{}>",
+                escape(limit.metric.as_str()),
+                period_name(limit.period),
+                if is_exceeded {
+                    " exceeded=\"true\""
+                } else {
+                    ""
+                }
+            ));
+            if let Some((start, end)) = period_bounds(limit.period, now) {
+                xml.push_str(&format!(
+                    "<period_start>{}</period_start><period_end>{}</period_end>",
+                    timestamp(start),
+                    timestamp(end)
+                ));
+            }
+            xml.push_str(&format!(
+                "<max_value>{}</max_value><current_value>{}</current_value></usage_report>",
+                limit.max_value, limit.current_value
+            ));
+        }
+        xml.push_str("</usage_reports>");
+    }
+
+    xml.push_str("</status>");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn period_name(period: Period) -> &'static str {
+    match period {
+        Period::Minute => "minute",
+        Period::Hour => "hour",
+        Period::Day => "day",
+        Period::Week => "week",
+        Period::Month => "month",
+        Period::Year => "year",
+        Period::Eternity => "eternity",
+    }
+}
+
+// Days since the UNIX epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = (year - era * 400) as u64;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era as i64 - 719_468) as u64
+}
+
+// Date in the proleptic Gregorian calendar of a number of days since the UNIX epoch.
+fn civil_from_days(days: u64) -> (i64, u64, u64) {
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = (days - era * 146_097) as u64;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Start and end, in seconds since the UNIX epoch, of the period including `now`, with weeks
+// starting on Mondays.
+fn period_bounds(period: Period, now: u64) -> Option<(u64, u64)> {
+    let days = now / SECS_PER_DAY;
+    let (year, month, _) = civil_from_days(days);
+    let bounds = match period {
+        Period::Minute => (now - now % 60, now - now % 60 + 60),
+        Period::Hour => (now - now % 3600, now - now % 3600 + 3600),
+        Period::Day => (days * SECS_PER_DAY, (days + 1) * SECS_PER_DAY),
+        Period::Week => {
+            // the epoch was a Thursday
+            let monday = days - (days + 3) % 7;
+            (monday * SECS_PER_DAY, (monday + 7) * SECS_PER_DAY)
+        }
+        Period::Month => {
+            let (next_year, next_month) = if month == 12 {
+                (year + 1, 1)
+            } else {
+                (year, month + 1)
+            };
+            (
+                days_from_civil(year, month, 1) * SECS_PER_DAY,
+                days_from_civil(next_year, next_month, 1) * SECS_PER_DAY,
+            )
+        }
+        Period::Year => (
+            days_from_civil(year, 1, 1) * SECS_PER_DAY,
+            days_from_civil(year + 1, 1, 1) * SECS_PER_DAY,
+        ),
+        Period::Eternity => return None,
+    };
+
+    Some(bounds)
+}
+
+// Formats a timestamp the way the 3scale backend does, ie. "2021-03-01 10:00:00 +0000".
+fn timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+    let secs_of_day = secs % SECS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} +0000",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::authrep::response::AuthRepResponse;
+    use core::convert::TryFrom;
+
+    // 2021-03-03T10:00:30Z, a Wednesday
+    const NOW: u64 = 1_614_765_630;
+
+    fn call(method: &str, path: &str, body: Option<&str>) -> HttpCall {
+        HttpCall {
+            token: 1,
+            upstream: "backend".to_string(),
+            headers: vec![
+                (":method".to_string(), method.to_string()),
+                (":path".to_string(), path.to_string()),
+            ],
+            body: body.map(|body| body.as_bytes().to_vec()),
+            timeout_ms: 5000,
+        }
+    }
+
+    fn backend() -> MockBackend {
+        let mut backend = MockBackend::new();
+        backend
+            .add_service("1", "token")
+            .add_user_key("1", "secret")
+            .add_app_id("1", "app", &["key"])
+            .add_limit("1", "secret", "hits", Period::Minute, 2)
+            .add_limit("1", "secret", "hits", Period::Month, 100);
+        backend
+    }
+
+    #[test]
+    fn it_formats_periods_like_the_backend() {
+        assert_eq!(timestamp(NOW), "2021-03-03 10:00:30 +0000");
+        let bounds = |period| {
+            let (start, end) = period_bounds(period, NOW).unwrap();
+            (timestamp(start), timestamp(end))
+        };
+        assert_eq!(
+            bounds(Period::Week),
+            (
+                "2021-03-01 00:00:00 +0000".to_string(),
+                "2021-03-08 00:00:00 +0000".to_string()
+            )
+        );
+        assert_eq!(
+            bounds(Period::Month),
+            (
+                "2021-03-01 00:00:00 +0000".to_string(),
+                "2021-04-01 00:00:00 +0000".to_string()
+            )
+        );
+        assert_eq!(bounds(Period::Year).1, "2022-01-01 00:00:00 +0000");
+        assert_eq!(period_bounds(Period::Eternity, NOW), None);
+    }
+
+    #[test]
+    fn it_authorizes_and_reports_within_limits() {
+        let mut backend = backend();
+        let authrep = "/transactions/authrep.xml?service_id=1&service_token=token&user_key=secret&usage%5Bhits%5D=1";
+
+        for _ in 0..2 {
+            let (status, body) = backend.handle(&call("GET", authrep, None), NOW);
+            assert_eq!(status, 200);
+            assert!(AuthRepResponse::try_from(body.as_str())
+                .unwrap()
+                .is_authorized());
+        }
+        let (status, body) = backend.handle(&call("GET", authrep, None), NOW);
+        assert_eq!(status, 409);
+        match AuthRepResponse::try_from(body.as_str()).unwrap() {
+            AuthRepResponse::Status(status) => {
+                assert!(status.is_limited());
+                assert_eq!(status.retry_after(NOW), Some(30));
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert_eq!(backend.usage("1", "secret", "hits"), 2);
+
+        // a new minute
+        let (status, _) = backend.handle(&call("GET", authrep, None), NOW + 30);
+        assert_eq!(status, 200);
+
+        let (status, _) = backend.handle(
+            &call(
+                "POST",
+                "/transactions.xml",
+                Some("service_id=1&service_token=token&transactions%5B0%5D%5Bapp_id%5D=app&transactions%5B0%5D%5Busage%5D%5Bhits%5D=5&transactions%5B1%5D%5Buser_key%5D=secret&transactions%5B1%5D%5Busage%5D%5Bhits%5D=2"),
+            ),
+            NOW + 30,
+        );
+        assert_eq!(status, 202);
+        assert_eq!(backend.usage("1", "app", "hits"), 5);
+        assert_eq!(backend.usage("1", "secret", "hits"), 5);
+    }
+
+    #[test]
+    fn it_rejects_invalid_credentials() {
+        let mut backend = backend();
+        let mut status_of = |path: &str| backend.handle(&call("GET", path, None), NOW).0;
+
+        assert_eq!(
+            status_of("/transactions/authrep.xml?service_id=1&service_token=nope&user_key=secret"),
+            403
+        );
+        assert_eq!(
+            status_of("/transactions/authrep.xml?service_id=2&service_token=token&user_key=secret"),
+            404
+        );
+        assert_eq!(
+            status_of("/transactions/authrep.xml?service_id=1&service_token=token&user_key=nope"),
+            403
+        );
+        assert_eq!(
+            status_of("/transactions/authrep.xml?service_id=1&service_token=token&app_id=nope"),
+            404
+        );
+        assert_eq!(
+            status_of(
+                "/transactions/authorize.xml?service_id=1&service_token=token&app_id=app&app_key=nope"
+            ),
+            409
+        );
+        assert_eq!(
+            status_of(
+                "/transactions/authorize.xml?service_id=1&service_token=token&app_id=app&app_key=key"
+            ),
+            200
+        );
+
+        let (_, body) = backend.handle(
+            &call(
+                "GET",
+                "/transactions/authrep.xml?service_id=1&service_token=token&user_key=nope",
+                None,
+            ),
+            NOW,
+        );
+        match AuthRepResponse::try_from(body.as_str()).unwrap() {
+            AuthRepResponse::Error(error) => {
+                assert_eq!(error.code(), "user_key_invalid");
+                assert_eq!(error.message(), "user key \"nope\" is invalid");
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+}
@@ -0,0 +1,735 @@
+// Emulation of the proxy-wasm host for native tests.
+//
+// The hostcalls the SDK imports are defined here, backed by per-thread state that tests set
+// up and inspect through `TestHost`, so that contexts can be driven by calling their
+// callbacks just like the host would.
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::{BufferType, LogLevel, MapType, MetricType, Status};
+
+// 2020-09-13T12:26:40Z
+const DEFAULT_TIME_SECS: u64 = 1_600_000_000;
+
+/// An HTTP call dispatched to the host.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpCall {
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub timeout_ms: u32,
+}
+
+impl HttpCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(self.headers.as_slice(), name)
+    }
+}
+
+/// A response sent to the downstream client instead of forwarding the request.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LocalResponse {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct CallResponse {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    time_nanos: u64,
+    tick_period_ms: u32,
+    vm_configuration: Option<Vec<u8>>,
+    plugin_configuration: Option<Vec<u8>>,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+    properties: HashMap<Vec<String>, Vec<u8>>,
+    // values and CAS by key
+    shared_data: HashMap<String, (Vec<u8>, u32)>,
+    // queue names and items, the id of a queue being its position
+    queues: Vec<(String, VecDeque<Vec<u8>>)>,
+    // metric names, types and values, the id of a metric being its position
+    metrics: Vec<(String, MetricType, u64)>,
+    next_call_token: u32,
+    calls: Vec<HttpCall>,
+    // response of the call being delivered
+    call_response: Option<CallResponse>,
+    local_response: Option<LocalResponse>,
+    resumed: bool,
+    done: bool,
+}
+
+thread_local! {
+    static HOST: RefCell<HostState> = RefCell::new(HostState::default());
+}
+
+fn with_host<R>(f: impl FnOnce(&mut HostState) -> R) -> R {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+fn find<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn to_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|&(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Handle on the emulated host of the current thread, which is reset when one is created.
+///
+/// Each test runs on its own thread, so tests do not see each other's host state.
+#[derive(Debug)]
+pub(crate) struct TestHost(());
+
+impl TestHost {
+    pub fn new() -> Self {
+        with_host(|host| {
+            *host = HostState {
+                time_nanos: DEFAULT_TIME_SECS * 1_000_000_000,
+                next_call_token: 1,
+                ..Default::default()
+            }
+        });
+        Self(())
+    }
+
+    /// Seconds since the UNIX epoch according to the host.
+    pub fn now(&self) -> u64 {
+        with_host(|host| host.time_nanos / 1_000_000_000)
+    }
+
+    pub fn set_time(&self, secs: u64) {
+        with_host(|host| host.time_nanos = secs * 1_000_000_000);
+    }
+
+    pub fn advance_time(&self, secs: u64) {
+        with_host(|host| host.time_nanos += secs * 1_000_000_000);
+    }
+
+    pub fn tick_period_ms(&self) -> u32 {
+        with_host(|host| host.tick_period_ms)
+    }
+
+    /// Shared data stored by any context, by key, leaving out cleared values.
+    pub fn shared_data(&self) -> HashMap<String, Vec<u8>> {
+        with_host(|host| {
+            host.shared_data
+                .iter()
+                .filter(|(_, (value, _))| !value.is_empty())
+                .map(|(key, (value, _))| (key.clone(), value.clone()))
+                .collect()
+        })
+    }
+
+    /// Number of keys ever stored in shared data, cleared or not, as the host never drops them.
+    pub fn shared_data_keys(&self) -> usize {
+        with_host(|host| host.shared_data.len())
+    }
+
+    /// Value of a metric defined by any context, if it was.
+    pub fn metric(&self, name: &str) -> Option<u64> {
+        with_host(|host| {
+            host.metrics
+                .iter()
+                .find(|(metric, _, _)| metric == name)
+                .map(|&(_, _, value)| value)
+        })
+    }
+
+    pub fn set_vm_configuration(&self, configuration: &str) {
+        with_host(|host| host.vm_configuration = Some(configuration.as_bytes().to_vec()));
+    }
+
+    pub fn set_plugin_configuration(&self, configuration: &str) {
+        with_host(|host| host.plugin_configuration = Some(configuration.as_bytes().to_vec()));
+    }
+
+    /// Sets the headers of the request being handled, clearing the outcome of the last one.
+    pub fn set_request_headers(&self, headers: &[(&str, &str)]) {
+        with_host(|host| {
+            host.request_headers = to_pairs(headers);
+            host.response_headers.clear();
+            host.local_response = None;
+            host.resumed = false;
+        });
+    }
+
+    pub fn set_property(&self, path: &[&str], value: &[u8]) {
+        let path = path.iter().map(|segment| segment.to_string()).collect();
+        with_host(|host| host.properties.insert(path, value.to_vec()));
+    }
+
+    pub fn response_headers(&self) -> Vec<(String, String)> {
+        with_host(|host| host.response_headers.clone())
+    }
+
+    /// Takes the HTTP calls dispatched since the last time they were taken.
+    pub fn take_calls(&self) -> Vec<HttpCall> {
+        with_host(|host| std::mem::take(&mut host.calls))
+    }
+
+    pub fn local_response(&self) -> Option<LocalResponse> {
+        with_host(|host| host.local_response.clone())
+    }
+
+    /// Whether the request was resumed after being paused.
+    pub fn resumed(&self) -> bool {
+        with_host(|host| host.resumed)
+    }
+
+    /// Whether the root context signalled it is done after being asked to finish.
+    pub fn done(&self) -> bool {
+        with_host(|host| host.done)
+    }
+
+    /// Delivers the response to a dispatched call to the context that dispatched it.
+    pub fn respond<C: Context + ?Sized>(
+        &self,
+        ctx: &mut C,
+        call_token: u32,
+        status: u32,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) {
+        let mut response = CallResponse {
+            headers: vec![(":status".to_string(), status.to_string())],
+            body: body.to_vec(),
+        };
+        response.headers.extend(to_pairs(headers));
+        let num_headers = response.headers.len();
+        with_host(|host| host.call_response = Some(response));
+
+        ctx.on_http_call_response(call_token, num_headers, body.len(), 0);
+
+        with_host(|host| host.call_response = None);
+    }
+}
+
+// Header maps are serialized as the number of pairs, the sizes of each key and value, and
+// then the keys and values themselves, each followed by a NUL byte.
+fn serialize_map(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut bytes = (pairs.len() as u32).to_le_bytes().to_vec();
+    for (key, value) in pairs {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    }
+    for (key, value) in pairs {
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn deserialize_map(bytes: &[u8]) -> Vec<(String, String)> {
+    let read_u32 = |at: usize| {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&bytes[at..at + 4]);
+        u32::from_le_bytes(word) as usize
+    };
+    if bytes.len() < 4 {
+        return vec![];
+    }
+
+    let count = read_u32(0);
+    let mut pos = 4 + count * 8;
+    let mut string = |size: usize| {
+        let s = String::from_utf8_lossy(&bytes[pos..pos + size]).into_owned();
+        pos += size + 1;
+        s
+    };
+    (0..count)
+        .map(|n| {
+            let (key_size, value_size) = (read_u32(4 + n * 8), read_u32(8 + n * 8));
+            let key = string(key_size);
+            (key, string(value_size))
+        })
+        .collect()
+}
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(slice(data, size)).into_owned()
+}
+
+// Hands out a copy of the bytes to the SDK, which takes ownership of it as a `Vec<u8>` with
+// the same length and capacity.
+unsafe fn return_bytes(bytes: &[u8], data: *mut *mut u8, size: *mut usize) {
+    let bytes = bytes.to_vec().into_boxed_slice();
+    *size = bytes.len();
+    *data = Box::into_raw(bytes) as *mut u8;
+}
+
+fn map(host: &HostState, map_type: MapType) -> Option<&[(String, String)]> {
+    match map_type {
+        MapType::HttpRequestHeaders => Some(host.request_headers.as_slice()),
+        MapType::HttpResponseHeaders => Some(host.response_headers.as_slice()),
+        MapType::HttpCallResponseHeaders => host
+            .call_response
+            .as_ref()
+            .map(|response| response.headers.as_slice()),
+        _ => None,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_log(
+    level: LogLevel,
+    message_data: *const u8,
+    message_size: usize,
+) -> Status {
+    eprintln!("[{:?}] {}", level, string(message_data, message_size));
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    *return_time = with_host(|host| host.time_nanos);
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    with_host(|host| host.tick_period_ms = period);
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    with_host(|host| {
+        let buffer = match buffer_type {
+            BufferType::VmConfiguration => host.vm_configuration.as_deref(),
+            BufferType::PluginConfiguration => host.plugin_configuration.as_deref(),
+            BufferType::HttpCallResponseBody => host
+                .call_response
+                .as_ref()
+                .map(|response| response.body.as_slice()),
+            _ => None,
+        };
+        match buffer {
+            Some(buffer) => {
+                let start = start.min(buffer.len());
+                let end = start.saturating_add(max_size).min(buffer.len());
+                return_bytes(&buffer[start..end], return_buffer_data, return_buffer_size);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    with_host(|host| match map(host, map_type) {
+        Some(pairs) => {
+            return_bytes(&serialize_map(pairs), return_map_data, return_map_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    with_host(
+        |host| match map(host, map_type).and_then(|pairs| find(pairs, key.as_str())) {
+            Some(value) => {
+                return_bytes(value.as_bytes(), return_value_data, return_value_size);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        },
+    )
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = string(value_data, value_size);
+    with_host(|host| {
+        let pairs = match map_type {
+            MapType::HttpRequestHeaders => &mut host.request_headers,
+            MapType::HttpResponseHeaders => &mut host.response_headers,
+            _ => return Status::BadArgument,
+        };
+        pairs.retain(|(name, _)| !name.eq_ignore_ascii_case(key.as_str()));
+        pairs.push((key, value));
+        Status::Ok
+    })
+}
+
+// Property paths are serialized as their segments, each followed by a NUL byte.
+#[no_mangle]
+unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = string(path_data, path_size);
+    let path = path
+        .trim_end_matches('\0')
+        .split('\0')
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    with_host(|host| match host.properties.get(&path) {
+        Some(value) => {
+            return_bytes(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    with_host(|host| match host.shared_data.get(&key) {
+        Some((value, cas)) => {
+            return_bytes(value, return_value_data, return_value_size);
+            *return_cas = *cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+// A CAS of 0 stores the value unconditionally, like the host does.
+#[no_mangle]
+unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| {
+        let current = host.shared_data.get(&key).map(|(_, cas)| *cas);
+        if cas != 0 && current != Some(cas) {
+            return Status::CasMismatch;
+        }
+        let next_cas = current.unwrap_or_default().wrapping_add(1).max(1);
+        host.shared_data.insert(key, (value, next_cas));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with_host(
+        |host| match host.queues.iter().position(|(queue, _)| *queue == name) {
+            Some(id) => id,
+            None => {
+                host.queues.push((name, VecDeque::new()));
+                host.queues.len() - 1
+            }
+        },
+    ) as u32;
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    match with_host(|host| host.queues.iter().position(|(queue, _)| *queue == name)) {
+        Some(id) => {
+            *return_id = id as u32;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    with_host(|host| match host.queues.get_mut(queue_id as usize) {
+        Some((_, items)) => match items.pop_front() {
+            Some(item) => {
+                return_bytes(item.as_slice(), return_value_data, return_value_size);
+                Status::Ok
+            }
+            None => Status::Empty,
+        },
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| match host.queues.get_mut(queue_id as usize) {
+        Some((_, items)) => {
+            items.push_back(value);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_continue_request() -> Status {
+    with_host(|host| host.resumed = true);
+    Status::Ok
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    _status_code_details_data: *const u8,
+    _status_code_details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    let response = LocalResponse {
+        status: status_code,
+        headers: deserialize_map(slice(headers_data, headers_size)),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    with_host(|host| host.local_response = Some(response));
+    Status::Ok
+}
+
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    _trailers_data: *const u8,
+    _trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let mut call = HttpCall {
+        token: 0,
+        upstream: string(upstream_data, upstream_size),
+        headers: deserialize_map(slice(headers_data, headers_size)),
+        body: if body_data.is_null() {
+            None
+        } else {
+            Some(slice(body_data, body_size).to_vec())
+        },
+        timeout_ms: timeout,
+    };
+    *return_token = with_host(|host| {
+        call.token = host.next_call_token;
+        host.next_call_token += 1;
+        host.calls.push(call);
+        host.next_call_token - 1
+    });
+    Status::Ok
+}
+
+// Defining a metric again returns the id it already has.
+#[no_mangle]
+unsafe extern "C" fn proxy_define_metric(
+    metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = with_host(|host| {
+        match host
+            .metrics
+            .iter()
+            .position(|(metric, _, _)| *metric == name)
+        {
+            Some(id) => id,
+            None => {
+                host.metrics.push((name, metric_type, 0));
+                host.metrics.len() - 1
+            }
+        }
+    }) as u32;
+    Status::Ok
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    with_host(|host| match host.metrics.get(metric_id as usize) {
+        Some(&(_, _, value)) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, _, current)) => {
+            *current = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some((_, MetricType::Histogram, _)) => Status::BadArgument,
+        Some((_, _, current)) => {
+            *current = (*current as i64).saturating_add(offset).max(0) as u64;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn proxy_done() -> Status {
+    with_host(|host| host.done = true);
+    Status::Ok
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_header_maps() {
+        let pairs = vec![
+            (":path".to_string(), "/".to_string()),
+            ("empty".to_string(), "".to_string()),
+        ];
+        assert_eq!(deserialize_map(&serialize_map(&pairs)), pairs);
+        assert!(deserialize_map(&[]).is_empty());
+    }
+
+    struct Probe;
+
+    impl Context for Probe {}
+
+    #[test]
+    fn it_answers_hostcalls_from_its_state() {
+        let host = TestHost::new();
+        let probe = Probe;
+
+        host.set_time(1_000);
+        host.advance_time(5);
+        assert_eq!(
+            probe.get_current_time(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_005)
+        );
+
+        host.set_property(&["metadata", "filter_metadata"], b"value");
+        assert_eq!(
+            probe.get_property(vec!["metadata", "filter_metadata"]),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(probe.get_property(vec!["metadata"]), None);
+
+        assert_eq!(probe.set_shared_data("key", Some(b"one"), None), Ok(()));
+        let (value, cas) = probe.get_shared_data("key");
+        assert_eq!(value, Some(b"one".to_vec()));
+        assert_eq!(probe.set_shared_data("key", Some(b"two"), cas), Ok(()));
+        assert_eq!(
+            probe.set_shared_data("key", Some(b"three"), cas),
+            Err(Status::CasMismatch)
+        );
+
+        let queue = probe.register_shared_queue("queue");
+        assert_eq!(probe.enqueue_shared_queue(queue, Some(b"item")), Ok(()));
+        assert_eq!(
+            probe.dequeue_shared_queue(queue),
+            Ok(Some(b"item".to_vec()))
+        );
+        assert_eq!(probe.dequeue_shared_queue(queue), Ok(None));
+
+        let counter = proxy_wasm::hostcalls::define_metric(MetricType::Counter, "counter");
+        assert_eq!(
+            proxy_wasm::hostcalls::define_metric(MetricType::Counter, "counter"),
+            counter
+        );
+        let counter = counter.unwrap();
+        assert_eq!(proxy_wasm::hostcalls::increment_metric(counter, 2), Ok(()));
+        assert_eq!(proxy_wasm::hostcalls::get_metric(counter), Ok(2));
+        assert_eq!(host.metric("counter"), Some(2));
+        assert_eq!(host.metric("gauge"), None);
+
+        assert!(!host.done());
+        probe.done();
+        assert!(host.done());
+    }
+}
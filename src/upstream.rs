@@ -73,11 +73,22 @@ impl Upstream {
             Some(bytes) => String::from_utf8_lossy(bytes),
             None => "(nothing)".into(),
         };
+        // credentials sent in headers are left out of the logs
+        let logged_hdrs = hdrs
+            .iter()
+            .map(|&(name, value)| {
+                if name.eq_ignore_ascii_case("authorization") {
+                    (name, "(redacted)")
+                } else {
+                    (name, value)
+                }
+            })
+            .collect::<Vec<_>>();
         log::debug!(
             "calling out {} (using {} scheme) with headers -> {:?} <- and body -> {:?} <-",
             name,
             scheme,
-            hdrs,
+            logged_hdrs,
             body_str.as_ref()
         );
         ctx.dispatch_http_call(name, hdrs, body, trailers, timeout)
@@ -155,29 +155,92 @@ impl Pairs {
         self.pairs
             .iter()
             .try_fold(core::mem::size_of::<u32>(), |acc, (k, v)| {
+                // k and v lens, then k and v zero-terminated
                 acc.checked_add(
                     k.len()
                         .saturating_add(v.len())
                         .saturating_add(2)
-                        .saturating_mul(core::mem::size_of::<u32>()),
+                        .saturating_add(2 * core::mem::size_of::<u32>()),
                 )
             })
     }
 
+    // Encodes the pairs into a new buffer of exactly the required length.
+    // Err(usize::MAX) if some length does not fit the u32 it is encoded as.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, usize> {
+        let len = |len: usize| {
+            u32::try_from(len)
+                .map(u32::to_le_bytes)
+                .map_err(|_| usize::MAX)
+        };
+        let mut b = Vec::with_capacity(self.required_buffer_length().ok_or(usize::MAX)?);
+        // number of pairs
+        b.extend_from_slice(&len(self.pairs.len())?);
+        // all keylen, valuelen
+        for (k, v) in &self.pairs {
+            b.extend_from_slice(&len(k.len())?);
+            b.extend_from_slice(&len(v.len())?);
+        }
+        // zero-terminated keys and values
+        for (k, v) in &self.pairs {
+            b.extend_from_slice(k.as_bytes());
+            b.push(0);
+            b.extend_from_slice(v);
+            b.push(0);
+        }
+
+        Ok(b)
+    }
+
+    // Returns Self or error with a hint to the very minimum required buffer length, as
+    // `PairsRef::new` does, copying the keys and values out of the buffer.
+    pub fn decode(b: &[u8]) -> Result<Self, usize> {
+        PairsRef::new(b).map(Self::from)
+    }
+
+    // Encodes the pairs into a buffer, returns error with amount of bytes short of requirements, Ok(written_bytes) otherwise
+    // Err(usize::MAX) is there is no way this can work
+    pub fn encode(&self, b: &mut [u8]) -> Result<usize, usize> {
+        let buf_len = b.len();
+        let required_len = self.required_buffer_length().ok_or(usize::MAX)?;
+        if buf_len < required_len {
+            return Err(required_len - buf_len);
+        }
+        b[..required_len].copy_from_slice(self.to_bytes()?.as_slice());
+
+        Ok(required_len)
+    }
+}
+
+// Reads the little-endian u32 at an index counted in u32s, if it is within the buffer.
+fn read_u32(b: &[u8], idx: usize) -> Option<u32> {
+    let start = idx.checked_mul(core::mem::size_of::<u32>())?;
+    let end = start.checked_add(core::mem::size_of::<u32>())?;
+    b.get(start..end)
+        .and_then(|b| <[u8; core::mem::size_of::<u32>()]>::try_from(b).ok())
+        .map(u32::from_le_bytes)
+}
+
+/// A view of serialized pairs walking the buffer they are in, without copying them out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct PairsRef<'a> {
+    // exactly the bytes of the serialized pairs
+    b: &'a [u8],
+    pairs_len: usize,
+}
+
+impl<'a> PairsRef<'a> {
     // Returns Self or error with a hint to the very minimum required buffer length.
     // Note: minimum required length can vary as data is parsed, so buffers should at least ensure
     //       that many bytes are available before calling again (for which there could be another
     //       bigger requirement).
-    // XXX TODO iterator without cloning parts of the buffer
-    pub fn decode(b: &[u8]) -> Result<Self, usize> {
+    // Err(usize::MAX) if there is no way this can work.
+    // Keys that are not UTF-8 are not an error: `iter` skips them, while `iter_bytes` and
+    // `Pairs::decode`, which decodes them lossily, do not.
+    pub fn new(b: &'a [u8]) -> Result<Self, usize> {
         let buf_len = b.len();
         // ensure min length of 1 u32
-        if buf_len < core::mem::size_of::<u32>() {
-            return Err(core::mem::size_of::<u32>());
-        }
-        let mut b32 = b as *const _ as *const u32;
-        // read number of pairs
-        let pairs_len = unsafe { *b32 } as usize;
+        let pairs_len = read_u32(b, 0).ok_or(core::mem::size_of::<u32>())? as usize;
         // minimum required length is now 1 + pairs_len * 2 (for k and v lens) * sizeof(u32) + pairs_len * 2 (for k and v zero-termination) * sizeof(u8)
         let required_len = core::mem::size_of::<u32>()
             .checked_add(
@@ -194,79 +257,136 @@ impl Pairs {
         if buf_len < required_len {
             return Err(required_len);
         }
-        let mut sizes = Vec::with_capacity(pairs_len);
         let required_len = (0..pairs_len)
-            .try_fold(required_len, |acc, _| {
-                let (k_len, v_len) = unsafe {
-                    b32 = b32.add(1);
-                    let k_len = *b32 as usize;
-                    b32 = b32.add(1);
-                    let v_len = *b32 as usize;
-                    sizes.push((k_len, v_len));
-                    (k_len, v_len)
-                };
+            .try_fold(required_len, |acc, idx| {
+                let k_len = read_u32(b, 1 + 2 * idx)? as usize;
+                let v_len = read_u32(b, 2 + 2 * idx)? as usize;
                 acc.checked_add(k_len.saturating_add(v_len))
             })
             .ok_or(usize::MAX)?;
         if buf_len < required_len {
             return Err(required_len - buf_len);
         }
-        let mut b8 = unsafe { b32.offset(1) } as *const u8;
-        let pairs =
-            sizes
-                .into_iter()
-                .fold(Vec::with_capacity(pairs_len), |mut acc, (k_len, v_len)| {
-                    let s =
-                        String::from_utf8_lossy(unsafe { core::slice::from_raw_parts(b8, k_len) })
-                            .into_owned();
-                    unsafe { b8 = b8.add(k_len + 1) };
-                    let v = unsafe { core::slice::from_raw_parts(b8, v_len) }.to_owned();
-                    unsafe { b8 = b8.add(v_len + 1) };
-                    acc.push((s, v));
-                    acc
-                });
-
-        Ok(Self { pairs })
+
+        Ok(Self {
+            b: &b[..required_len],
+            pairs_len,
+        })
     }
 
-    // Encodes the pairs into a buffer, returns error with amount of bytes short of requirements, Ok(written_bytes) otherwise
-    // Err(usize::MAX) is there is no way this can work
-    pub fn encode(&self, b: &mut [u8]) -> Result<usize, usize> {
-        let buf_len = b.len();
-        let pairs_len = self.pairs.len();
-        let required_len = self.required_buffer_length().ok_or(usize::MAX)?;
-        if buf_len < required_len {
-            return Err(required_len - buf_len);
-        }
-        let mut b32 = b as *mut _ as *mut u32;
-        // write number of pairs
-        unsafe { *b32 = pairs_len as u32 };
-        // write all keylen, valuelen
-        for (k, v) in &self.pairs {
-            unsafe {
-                b32 = b32.add(1);
-                *b32 = k.len() as u32;
-                b32 = b32.add(1);
-                *b32 = v.len() as u32;
+    /// Returns the pairs a value holds, since Envoy serializes maps within maps this way,
+    /// such as with filter metadata.
+    ///
+    /// Values are taken to be pairs when they decode as such taking up exactly their length,
+    /// and values that happen to be a zero count, like a 0 integer, are not pairs.
+    pub fn nested(value: &'a [u8]) -> Option<Self> {
+        match Self::new(value) {
+            Ok(nested) if !nested.is_empty() && nested.as_bytes().len() == value.len() => {
+                Some(nested)
             }
+            _ => None,
         }
-        let mut b8 = b32 as *mut u8;
-        for (k, v) in &self.pairs {
-            unsafe {
-                std::ptr::copy_nonoverlapping(k.as_ptr(), b8, k.len());
-                // zero-terminate
-                b8 = b8.add(k.len());
-                *b8 = 0;
-                b8 = b8.add(1);
-                std::ptr::copy_nonoverlapping(v.as_ptr(), b8, v.len());
-                // zero-terminate
-                b8 = b8.add(v.len());
-                *b8 = 0;
-                b8 = b8.add(1);
-            }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs_len == 0
+    }
+
+    /// Returns the serialized pairs, which might be shorter than the buffer they came from.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.b
+    }
+
+    /// Iterates over the pairs with UTF-8 keys, which are the only ones keys can be looked
+    /// up for.
+    pub fn iter(&self) -> PairsIter<'a> {
+        PairsIter(self.iter_bytes())
+    }
+
+    /// Iterates over every pair, with keys as they are.
+    pub fn iter_bytes(&self) -> PairsBytesIter<'a> {
+        let data_start = core::mem::size_of::<u32>() * (1 + 2 * self.pairs_len);
+        PairsBytesIter {
+            sizes: &self.b[core::mem::size_of::<u32>()..data_start],
+            data: &self.b[data_start..],
         }
+    }
 
-        Ok(required_len)
+    pub fn get(&self, key: &str) -> Option<&'a [u8]> {
+        self.iter().find(|&(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+impl<'a> IntoIterator for PairsRef<'a> {
+    type Item = (&'a str, &'a [u8]);
+    type IntoIter = PairsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl From<PairsRef<'_>> for Pairs {
+    fn from(pairs: PairsRef<'_>) -> Self {
+        Self::new(
+            pairs
+                .iter_bytes()
+                .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.to_vec()))
+                .collect(),
+        )
+    }
+}
+
+/// Iterator over the keys and values of `PairsRef`, borrowing them from its buffer.
+#[derive(Clone, Debug)]
+pub(crate) struct PairsBytesIter<'a> {
+    // k and v lens left
+    sizes: &'a [u8],
+    // zero-terminated keys and values left
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for PairsBytesIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let k_len = read_u32(self.sizes, 0)? as usize;
+        let v_len = read_u32(self.sizes, 1)? as usize;
+        self.sizes = self.sizes.get(2 * core::mem::size_of::<u32>()..)?;
+        let k = self.data.get(..k_len)?;
+        let rest = self.data.get(k_len + 1..)?;
+        let v = rest.get(..v_len)?;
+        self.data = rest.get(v_len + 1..)?;
+        Some((k, v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.sizes.len() / (2 * core::mem::size_of::<u32>());
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for PairsBytesIter<'_> {}
+
+/// Iterator over the pairs of `PairsRef` with UTF-8 keys, borrowing them from its buffer.
+#[derive(Clone, Debug)]
+pub(crate) struct PairsIter<'a>(PairsBytesIter<'a>);
+
+impl<'a> Iterator for PairsIter<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .by_ref()
+            .find_map(|(k, v)| core::str::from_utf8(k).ok().map(|k| (k, v)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.0.size_hint().1)
     }
 }
 
@@ -578,4 +698,138 @@ mod test {
         let exp = exp.unwrap();
         println!("exp is {}", exp);
     }
+
+    #[test]
+    fn it_round_trips_encoded_pairs() {
+        let pairs = Pairs::new(vec![
+            ("iss".to_string(), b"https://keycloak".to_vec()),
+            ("exp".to_string(), 1614620927f64.to_ne_bytes().to_vec()),
+        ]);
+        let bytes = pairs.to_bytes().unwrap();
+        assert_eq!(bytes.len(), 4 + 2 * 8 + (3 + 16 + 2) + (3 + 8 + 2));
+        assert_eq!(Pairs::decode(bytes.as_slice()), Ok(pairs.clone()));
+
+        // into a buffer that is not aligned for u32
+        let mut buf = vec![0xaa; bytes.len() + 2];
+        assert_eq!(pairs.encode(&mut buf[1..]), Ok(bytes.len()));
+        assert_eq!(&buf[1..=bytes.len()], bytes.as_slice());
+        assert_eq!(buf[bytes.len() + 1], 0xaa);
+        assert_eq!(pairs.encode(&mut buf[..bytes.len() - 3]), Err(3));
+
+        let bytes = Pairs::new(vec![("k".to_string(), b"v".to_vec())])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(bytes, b"\x01\0\0\0\x01\0\0\0\x01\0\0\0k\0v\0".to_vec());
+    }
+
+    #[test]
+    fn it_decodes_nested_pairs() {
+        let verified_jwt = Pairs::new(vec![
+            ("azp".to_string(), b"test".to_vec()),
+            ("exp".to_string(), 1614620927f64.to_ne_bytes().to_vec()),
+        ]);
+        let jwt_authn = Pairs::new(vec![(
+            "verified_jwt".to_string(),
+            verified_jwt.to_bytes().unwrap(),
+        )]);
+        let metadata = Pairs::new(vec![
+            (
+                "envoy.filters.http.jwt_authn".to_string(),
+                jwt_authn.to_bytes().unwrap(),
+            ),
+            ("other".to_string(), b"not pairs".to_vec()),
+            ("zero".to_string(), 0u32.to_ne_bytes().to_vec()),
+        ]);
+
+        let bytes = metadata.to_bytes().unwrap();
+        let nested = PairsRef::new(bytes.as_slice()).unwrap();
+        let jwt_authn = nested
+            .get("envoy.filters.http.jwt_authn")
+            .and_then(PairsRef::nested)
+            .expect("expected nested pairs");
+        let verified_jwt = jwt_authn
+            .get("verified_jwt")
+            .and_then(PairsRef::nested)
+            .expect("expected nested pairs");
+        assert_eq!(verified_jwt.get("azp"), Some(&b"test"[..]));
+        assert_eq!(
+            verified_jwt.get("exp"),
+            Some(&1614620927f64.to_ne_bytes()[..])
+        );
+        assert!(verified_jwt.get("azp").and_then(PairsRef::nested).is_none());
+        assert!(nested.get("other").and_then(PairsRef::nested).is_none());
+        assert_eq!(nested.get("zero"), Some(&[0, 0, 0, 0][..]));
+        assert!(nested.get("zero").and_then(PairsRef::nested).is_none());
+        assert_eq!(PairsRef::new(&[1, 0]), Err(4));
+    }
+
+    #[test]
+    fn it_walks_pairs_in_place() {
+        let pairs = Pairs::new(vec![
+            ("azp".to_string(), b"test".to_vec()),
+            ("empty".to_string(), vec![]),
+        ]);
+        let mut bytes = pairs.to_bytes().unwrap();
+        let len = bytes.len();
+        bytes.extend_from_slice(b"trailing");
+        // values are not aligned anymore
+        bytes.insert(0, 0);
+
+        let view = PairsRef::new(&bytes[1..]).unwrap();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.as_bytes().len(), len);
+        assert_eq!(
+            view.iter().collect::<Vec<_>>(),
+            vec![("azp", &b"test"[..]), ("empty", &b""[..])]
+        );
+        assert_eq!(view.get("azp"), Some(&b"test"[..]));
+        assert_eq!(view.get("aud"), None);
+        assert_eq!(Pairs::from(view), pairs);
+    }
+
+    #[test]
+    fn it_checks_bounds_of_pairs() {
+        let bytes = Pairs::new(vec![("azp".to_string(), b"test".to_vec())])
+            .to_bytes()
+            .unwrap();
+        assert_eq!(PairsRef::new(&bytes[..2]), Err(4));
+        assert_eq!(PairsRef::new(&bytes[..8]), Err(14));
+        assert_eq!(PairsRef::new(&bytes[..bytes.len() - 1]), Err(1));
+
+        // u32::MAX pairs
+        assert!(PairsRef::new(&[0xff; 16]).is_err());
+    }
+
+    #[test]
+    fn it_handles_keys_that_are_not_utf8() {
+        let mut bytes = Pairs::new(vec![
+            ("azp".to_string(), b"test".to_vec()),
+            ("aud".to_string(), b"other".to_vec()),
+        ])
+        .to_bytes()
+        .unwrap();
+        // first byte of the first key
+        bytes[20] = 0xff;
+
+        // skipped when borrowing keys as str, which could not match them anyway
+        let view = PairsRef::new(bytes.as_slice()).unwrap();
+        assert_eq!(
+            view.iter().collect::<Vec<_>>(),
+            vec![("aud", &b"other"[..])]
+        );
+        assert_eq!(view.get("\u{fffd}zp"), None);
+        assert_eq!(
+            view.iter_bytes().collect::<Vec<_>>(),
+            vec![(&b"\xffzp"[..], &b"test"[..]), (&b"aud"[..], &b"other"[..])]
+        );
+
+        // decoded lossily when copied
+        assert_eq!(
+            Pairs::decode(bytes.as_slice()),
+            Ok(Pairs::new(vec![
+                ("\u{fffd}zp".to_string(), b"test".to_vec()),
+                ("aud".to_string(), b"other".to_vec()),
+            ]))
+        );
+    }
 }